@@ -0,0 +1,560 @@
+use crate::id_map::{IdMap, Squash, DEFAULT_ANON_ID};
+use crate::tls::{ClientTls, ServerTls};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "[::1]:50051";
+const DEFAULT_SERVER_URL: &str = "http://[::1]:50051";
+const DEFAULT_ATTR_TTL: Duration = Duration::from_secs(1);
+const DEFAULT_ENTRY_TTL: Duration = Duration::from_secs(1);
+const DEFAULT_CACHE_SIZE_MIB: usize = 64;
+const DEFAULT_READAHEAD: u64 = 8;
+// a task is started for every block read ahead, so they are kept to 32 MiB
+const MAX_READAHEAD: u64 = 256;
+const DEFAULT_DISK_CACHE_SIZE_MIB: u64 = 1024;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRIES: u32 = 3;
+
+pub fn usage(exe_name: &str) {
+    println!("usage: {exe_name} server [options]");
+    println!("       {exe_name} client [options] <mountpoint>");
+    println!();
+    println!("server options:");
+    println!("    --listen <addr>     address to listen on ($SERVER_ADDRESS, default: {DEFAULT_LISTEN_ADDRESS})");
+    println!("    --root <dir>        directory to export to clients that do not name one");
+    println!("                        ($EXPORT_ROOT, default: current directory without --export)");
+    println!("    --export <name>=<dir>[,ro][,policy=<file>]");
+    println!("                        also export <dir> as <name>, read-only with ro, only to the");
+    println!("                        clients in <file> with policy, may be repeated");
+    println!("    --tls-cert <file>   serve over tls with this pem certificate");
+    println!("    --tls-key <file>    pem private key of --tls-cert");
+    println!("    --tls-client-ca <file>");
+    println!(
+        "                        only accept clients with a certificate signed by this pem ca"
+    );
+    println!("    --policy <file>     only serve the clients listed in <file> on exports without");
+    println!("                        their own policy, see README");
+    println!();
+    println!("client options:");
+    println!("    --server <url>      server to connect to, https:// for tls (default: {DEFAULT_SERVER_URL})");
+    println!("    --export <name>     mount the export <name> instead of the default one");
+    println!("    --list-exports      print the exports of the server instead of mounting");
+    println!("    --read-only         mount read-only");
+    println!("    --read-write        mount read-write (default)");
+    println!("    --allow-other       allow other users to access the mount");
+    println!("    --fs-name <name>    filesystem name shown in mount table (default: GrpcFs)");
+    println!("    --uid <uid>         report every file as owned by <uid>");
+    println!("    --gid <gid>         report every file as owned by group <gid>");
+    println!(
+        "    --attr-ttl <secs>   how long attributes are cached (default: {})",
+        DEFAULT_ATTR_TTL.as_secs_f64()
+    );
+    println!(
+        "    --entry-ttl <secs>  how long names are cached (default: {})",
+        DEFAULT_ENTRY_TTL.as_secs_f64()
+    );
+    println!("    --negative-ttl <secs>");
+    println!("                        how long missing names are cached (default: 0)");
+    println!("    --cache-size <MiB>  memory used to cache file data, 0 to disable (default: {DEFAULT_CACHE_SIZE_MIB})");
+    println!("    --readahead <n>     blocks read ahead on sequential reads, at most {MAX_READAHEAD} (default: {DEFAULT_READAHEAD})");
+    println!("    --disk-cache <dir>  keep file data in <dir> across mounts");
+    println!("    --disk-cache-size <MiB>");
+    println!("                        disk space used by --disk-cache (default: {DEFAULT_DISK_CACHE_SIZE_MIB})");
+    println!("    --hard              keep retrying while the server is down (default)");
+    println!("    --soft              fail with EIO once the retries run out");
+    println!(
+        "    --timeout <secs>    deadline of each request on a soft mount (default: {})",
+        DEFAULT_TIMEOUT.as_secs_f64()
+    );
+    println!(
+        "    --retries <n>       retries before a soft mount gives up (default: {DEFAULT_RETRIES})"
+    );
+    println!("    --tls-ca <file>     pem ca the server certificate must be signed by, needed for https://");
+    println!("    --tls-cert <file>   pem certificate to show to the server");
+    println!("    --tls-key <file>    pem private key of --tls-cert");
+    println!("    --tls-domain <name> name expected in the server certificate (default: host of --server)");
+    println!("    --token-file <file> send the bearer token in <file> to the server");
+    println!("    --id-map <file>     translate users and groups between this host and the server, see README");
+    println!("    --root-squash       make requests from root as --anon-uid/--anon-gid");
+    println!("    --all-squash        make all requests as --anon-uid/--anon-gid");
+    println!(
+        "    --anon-uid <uid>    server user of squashed requests (default: {DEFAULT_ANON_ID})"
+    );
+    println!(
+        "    --anon-gid <gid>    server group of squashed requests (default: {DEFAULT_ANON_ID})"
+    );
+}
+
+#[derive(Debug)]
+pub struct ServerArgs {
+    pub listen: SocketAddr,
+    // the default export has an empty name
+    pub exports: Vec<ExportArgs>,
+    pub tls: Option<ServerTls>,
+    // policy of the exports without one of their own
+    pub policy: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExportArgs {
+    pub name: String,
+    pub root: PathBuf,
+    pub read_only: bool,
+    pub policy: Option<PathBuf>,
+}
+
+impl ExportArgs {
+    // <name>=<dir>[,ro|rw][,policy=<file>]
+    fn parse(value: &str) -> Result<Self, String> {
+        let (name, options) = value
+            .split_once('=')
+            .ok_or_else(|| format!("invalid export '{value}': expected <name>=<dir>"))?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+        {
+            return Err(format!(
+                "invalid export name '{name}': only letters, digits, '.', '_' and '-' are allowed"
+            ));
+        }
+
+        let mut options = options.split(',');
+        let root = PathBuf::from(options.next().unwrap_or_default());
+        let mut export = ExportArgs {
+            name: name.to_string(),
+            root,
+            read_only: false,
+            policy: None,
+        };
+        for option in options {
+            match option {
+                "ro" => export.read_only = true,
+                "rw" => export.read_only = false,
+                _ => match option.strip_prefix("policy=") {
+                    Some(policy) if !policy.is_empty() => {
+                        export.policy = Some(PathBuf::from(policy))
+                    }
+                    _ => return Err(format!("unknown option '{option}' of export '{name}'")),
+                },
+            }
+        }
+        Ok(export)
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientArgs {
+    pub server: String,
+    pub mountpoint: PathBuf,
+    pub read_only: bool,
+    pub allow_other: bool,
+    pub fs_name: String,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub attr_ttl: Duration,
+    pub entry_ttl: Duration,
+    pub negative_ttl: Duration,
+    pub cache_size: usize,
+    pub readahead: u64,
+    pub disk_cache: Option<PathBuf>,
+    pub disk_cache_size: u64,
+    pub hard: bool,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub tls: Option<ClientTls>,
+    pub token: Option<String>,
+    pub id_map: IdMap,
+    // none for the default export
+    pub export: Option<String>,
+    pub list_exports: bool,
+}
+
+// walks over `--name value` style options
+struct Options<'a> {
+    args: std::slice::Iter<'a, String>,
+}
+
+impl<'a> Options<'a> {
+    fn new(args: &'a [String]) -> Self {
+        Options { args: args.iter() }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.args.next().map(|arg| arg.as_str())
+    }
+
+    fn value(&mut self, option: &str) -> Result<&'a str, String> {
+        self.next()
+            .ok_or_else(|| format!("option {option} requires a value"))
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self, option: &str) -> Result<T, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let value = self.value(option)?;
+        value
+            .parse()
+            .map_err(|e| format!("invalid value '{value}' for {option}: {e}"))
+    }
+
+    // durations are given in (possibly fractional) seconds
+    fn duration(&mut self, option: &str) -> Result<Duration, String> {
+        let secs: f64 = self.parse(option)?;
+        Duration::try_from_secs_f64(secs)
+            .map_err(|e| format!("invalid value '{secs}' for {option}: {e}"))
+    }
+}
+
+impl ServerArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut listen = std::env::var("SERVER_ADDRESS").ok();
+        let mut root = std::env::var("EXPORT_ROOT").ok();
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_client_ca = None;
+        let mut policy = None;
+        let mut exports = Vec::new();
+
+        let mut options = Options::new(args);
+        while let Some(option) = options.next() {
+            match option {
+                "--listen" => listen = Some(options.value(option)?.to_string()),
+                "--root" => root = Some(options.value(option)?.to_string()),
+                "--tls-cert" => tls_cert = Some(PathBuf::from(options.value(option)?)),
+                "--tls-key" => tls_key = Some(PathBuf::from(options.value(option)?)),
+                "--tls-client-ca" => tls_client_ca = Some(PathBuf::from(options.value(option)?)),
+                "--policy" => policy = Some(PathBuf::from(options.value(option)?)),
+                "--export" => exports.push(ExportArgs::parse(options.value(option)?)?),
+                _ => return Err(format!("unknown server option '{option}'")),
+            }
+        }
+
+        let listen = listen.unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string());
+        let listen = listen
+            .parse()
+            .map_err(|e| format!("invalid listen address '{listen}': {e}"))?;
+
+        // without --export the current directory is served as before
+        let root = match root {
+            Some(root) => Some(root),
+            None if exports.is_empty() => Some(String::from(".")),
+            None => None,
+        };
+        if let Some(root) = root {
+            exports.insert(
+                0,
+                ExportArgs {
+                    name: String::new(),
+                    root: PathBuf::from(root),
+                    read_only: false,
+                    policy: None,
+                },
+            );
+        }
+        for (i, export) in exports.iter().enumerate() {
+            if !export.root.is_dir() {
+                return Err(format!(
+                    "export root {} is not a directory",
+                    export.root.display()
+                ));
+            }
+            if exports[..i].iter().any(|other| other.name == export.name) {
+                return Err(format!("export '{}' is given twice", export.name));
+            }
+        }
+
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(ServerTls {
+                cert,
+                key,
+                client_ca: tls_client_ca,
+            }),
+            (None, None) if tls_client_ca.is_none() => None,
+            (None, None) => return Err(String::from("--tls-client-ca requires --tls-cert")),
+            _ => {
+                return Err(String::from(
+                    "--tls-cert and --tls-key must be given together",
+                ))
+            }
+        };
+
+        Ok(ServerArgs {
+            listen,
+            exports,
+            tls,
+            policy,
+        })
+    }
+}
+
+impl ClientArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut client_args = ClientArgs {
+            server: DEFAULT_SERVER_URL.to_string(),
+            mountpoint: PathBuf::new(),
+            read_only: false,
+            allow_other: false,
+            fs_name: String::from("GrpcFs"),
+            uid: None,
+            gid: None,
+            attr_ttl: DEFAULT_ATTR_TTL,
+            entry_ttl: DEFAULT_ENTRY_TTL,
+            negative_ttl: Duration::ZERO,
+            cache_size: DEFAULT_CACHE_SIZE_MIB << 20,
+            readahead: DEFAULT_READAHEAD,
+            disk_cache: None,
+            disk_cache_size: DEFAULT_DISK_CACHE_SIZE_MIB << 20,
+            hard: true,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            tls: None,
+            token: None,
+            id_map: IdMap::default(),
+            export: None,
+            list_exports: false,
+        };
+        let mut mountpoint = None;
+        let mut tls_ca = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_domain = None;
+        let mut id_map = None;
+        let mut squash = Squash::None;
+        let mut anon_uid = DEFAULT_ANON_ID;
+        let mut anon_gid = DEFAULT_ANON_ID;
+
+        let mut options = Options::new(args);
+        while let Some(option) = options.next() {
+            match option {
+                "--server" => client_args.server = options.value(option)?.to_string(),
+                "--export" => client_args.export = Some(options.value(option)?.to_string()),
+                "--list-exports" => client_args.list_exports = true,
+                "--read-only" => client_args.read_only = true,
+                "--read-write" => client_args.read_only = false,
+                "--allow-other" => client_args.allow_other = true,
+                "--fs-name" => client_args.fs_name = options.value(option)?.to_string(),
+                "--uid" => client_args.uid = Some(options.parse(option)?),
+                "--gid" => client_args.gid = Some(options.parse(option)?),
+                "--attr-ttl" => client_args.attr_ttl = options.duration(option)?,
+                "--entry-ttl" => client_args.entry_ttl = options.duration(option)?,
+                "--negative-ttl" => client_args.negative_ttl = options.duration(option)?,
+                "--cache-size" => {
+                    let mib: usize = options.parse(option)?;
+                    client_args.cache_size = mib
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| format!("{option} {mib} is too large"))?;
+                }
+                "--readahead" => client_args.readahead = options.parse(option)?,
+                "--disk-cache" => {
+                    client_args.disk_cache = Some(PathBuf::from(options.value(option)?))
+                }
+                "--disk-cache-size" => {
+                    let mib: u64 = options.parse(option)?;
+                    client_args.disk_cache_size = mib
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| format!("{option} {mib} is too large"))?;
+                }
+                "--hard" => client_args.hard = true,
+                "--soft" => client_args.hard = false,
+                "--timeout" => client_args.timeout = options.duration(option)?,
+                "--retries" => client_args.retries = options.parse(option)?,
+                "--tls-ca" => tls_ca = Some(PathBuf::from(options.value(option)?)),
+                "--tls-cert" => tls_cert = Some(PathBuf::from(options.value(option)?)),
+                "--tls-key" => tls_key = Some(PathBuf::from(options.value(option)?)),
+                "--tls-domain" => tls_domain = Some(options.value(option)?.to_string()),
+                "--token-file" => {
+                    let path = options.value(option)?;
+                    let token = std::fs::read_to_string(path)
+                        .map_err(|e| format!("failed to read {path}: {e}"))?;
+                    client_args.token = Some(token.trim().to_string());
+                }
+                "--id-map" => id_map = Some(PathBuf::from(options.value(option)?)),
+                "--root-squash" => squash = Squash::Root,
+                "--all-squash" => squash = Squash::All,
+                "--anon-uid" => anon_uid = options.parse(option)?,
+                "--anon-gid" => anon_gid = options.parse(option)?,
+                _ if option.starts_with('-') => {
+                    return Err(format!("unknown client option '{option}'"))
+                }
+                _ if mountpoint.is_some() => return Err(format!("unexpected argument '{option}'")),
+                _ => mountpoint = Some(PathBuf::from(option)),
+            }
+        }
+
+        if !client_args.server.starts_with("http://") && !client_args.server.starts_with("https://")
+        {
+            return Err(format!(
+                "invalid server url '{}': must start with http:// or https://",
+                client_args.server
+            ));
+        }
+        if let Err(e) = tonic::transport::Endpoint::from_shared(client_args.server.clone()) {
+            return Err(format!(
+                "invalid server url '{}': {}",
+                client_args.server, e
+            ));
+        }
+
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(String::from(
+                "--tls-cert and --tls-key must be given together",
+            ));
+        }
+        if client_args.server.starts_with("https://") {
+            let ca = tls_ca.ok_or("an https:// server requires --tls-ca")?;
+            client_args.tls = Some(ClientTls {
+                ca,
+                cert: tls_cert,
+                key: tls_key,
+                domain: tls_domain,
+            });
+        } else if tls_ca.is_some() || tls_cert.is_some() || tls_domain.is_some() {
+            return Err(String::from("tls options require an https:// server"));
+        }
+
+        client_args.id_map = IdMap::new(squash, anon_uid, anon_gid);
+        if let Some(id_map) = id_map {
+            client_args.id_map.load(&id_map)?;
+        }
+
+        if client_args.timeout.is_zero() {
+            return Err(String::from("--timeout must be greater than 0"));
+        }
+        if client_args.readahead > MAX_READAHEAD {
+            return Err(format!(
+                "--readahead must be at most {MAX_READAHEAD} blocks, got {}",
+                client_args.readahead
+            ));
+        }
+
+        // listing the exports does not mount anything
+        if client_args.list_exports {
+            if let Some(mountpoint) = mountpoint {
+                return Err(format!("unexpected argument '{}'", mountpoint.display()));
+            }
+            return Ok(client_args);
+        }
+
+        client_args.mountpoint = mountpoint.ok_or("no mountpoint given")?;
+        if !client_args.mountpoint.is_dir() {
+            return Err(format!(
+                "mountpoint {} is not a directory",
+                client_args.mountpoint.display()
+            ));
+        }
+
+        Ok(client_args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(args: &[&str]) -> Result<ClientArgs, String> {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.push(std::env::temp_dir().to_string_lossy().into_owned());
+        ClientArgs::parse(&args)
+    }
+
+    fn server(args: &[&str]) -> Result<ServerArgs, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        ServerArgs::parse(&args)
+    }
+
+    #[test]
+    fn options_without_their_value_are_refused() {
+        for option in ["--server", "--uid", "--timeout", "--token-file"] {
+            let args = [option.to_string()];
+            let error = ClientArgs::parse(&args).unwrap_err();
+            assert_eq!(error, format!("option {option} requires a value"));
+        }
+        let error = server(&["--listen", "127.0.0.1:0", "--export"]).unwrap_err();
+        assert_eq!(error, "option --export requires a value");
+    }
+
+    #[test]
+    fn invalid_values_are_named_in_the_error() {
+        let error = client(&["--timeout", "soon"]).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid value 'soon' for --timeout: invalid float literal"
+        );
+        let error = client(&["--attr-ttl", "-1"]).unwrap_err();
+        assert!(
+            error.starts_with("invalid value '-1' for --attr-ttl: "),
+            "{error}"
+        );
+        let error = client(&["--cache-size", "18446744073709551615"]).unwrap_err();
+        assert_eq!(error, "--cache-size 18446744073709551615 is too large");
+        let error = client(&["--timeout", "0"]).unwrap_err();
+        assert_eq!(error, "--timeout must be greater than 0");
+        let error = client(&["--readahead", "257"]).unwrap_err();
+        assert_eq!(error, "--readahead must be at most 256 blocks, got 257");
+        let error = client(&["--server", "grpc://localhost:50051"]).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid server url 'grpc://localhost:50051': must start with http:// or https://"
+        );
+
+        let error = server(&["--listen", "localhost"]).unwrap_err();
+        assert!(
+            error.starts_with("invalid listen address 'localhost': "),
+            "{error}"
+        );
+        let error = server(&["--export", "a b=/"]).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid export name 'a b': only letters, digits, '.', '_' and '-' are allowed"
+        );
+        let error = server(&["--export", "data=/,rx"]).unwrap_err();
+        assert_eq!(error, "unknown option 'rx' of export 'data'");
+    }
+
+    #[test]
+    fn unknown_options_and_extra_arguments_are_refused() {
+        let error = client(&["--mount-fast"]).unwrap_err();
+        assert_eq!(error, "unknown client option '--mount-fast'");
+        let error = client(&["/mnt"]).unwrap_err();
+        assert!(error.starts_with("unexpected argument '"), "{error}");
+        let error = client(&["--list-exports"]).unwrap_err();
+        assert!(error.starts_with("unexpected argument '"), "{error}");
+        let error = ClientArgs::parse(&[]).unwrap_err();
+        assert_eq!(error, "no mountpoint given");
+
+        let error = server(&["--root"]).unwrap_err();
+        assert_eq!(error, "option --root requires a value");
+        let error = server(&["--mount"]).unwrap_err();
+        assert_eq!(error, "unknown server option '--mount'");
+    }
+
+    #[test]
+    fn ids_must_fit_a_uid() {
+        let parsed = client(&["--uid", "1000", "--gid", "0"]).unwrap();
+        assert_eq!((parsed.uid, parsed.gid), (Some(1000), Some(0)));
+
+        for option in ["--uid", "--gid", "--anon-uid", "--anon-gid"] {
+            let error = client(&[option, "nobody"]).unwrap_err();
+            assert_eq!(
+                error,
+                format!("invalid value 'nobody' for {option}: invalid digit found in string")
+            );
+            let error = client(&[option, "-1"]).unwrap_err();
+            assert_eq!(
+                error,
+                format!("invalid value '-1' for {option}: invalid digit found in string")
+            );
+            let error = client(&[option, "4294967296"]).unwrap_err();
+            assert_eq!(
+                error,
+                format!(
+                    "invalid value '4294967296' for {option}: \
+                     number too large to fit in target type"
+                )
+            );
+        }
+    }
+}
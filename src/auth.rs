@@ -0,0 +1,283 @@
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tonic::{Request, Status};
+
+// the client sends its token in this metadata entry, as "Bearer <token>"
+pub const AUTHORIZATION_METADATA_KEY: &str = "authorization";
+
+// what an operation does with a path, checked against the caller's grants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    // stat a file, allowed on the directories leading to a granted subtree
+    Lookup,
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Rule {
+    // relative to the export root, empty for the whole export
+    subtree: PathBuf,
+    writable: bool,
+}
+
+// which parts of the export a client may use, looked up for each of its requests
+#[derive(Debug, Clone)]
+pub struct Grants {
+    // what the client was identified by, for file handles and logs
+    pub identity: Arc<str>,
+    rules: Arc<[Rule]>,
+}
+
+impl Grants {
+    // `path` is relative to the export root, with symlinks already resolved
+    pub fn check(&self, path: &Path, access: Access) -> Result<(), Status> {
+        // the most specific subtree decides, so a read-only subtree can sit in a writable one
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.subtree))
+            .max_by_key(|rule| rule.subtree.components().count());
+
+        match rule {
+            Some(rule) if access != Access::Write || rule.writable => Ok(()),
+            Some(_) => Err(denied(&self.identity, path, libc::EROFS)),
+            None if access == Access::Lookup
+                && self.rules.iter().any(|rule| rule.subtree.starts_with(path)) =>
+            {
+                Ok(())
+            }
+            None => Err(denied(&self.identity, path, libc::EACCES)),
+        }
+    }
+}
+
+fn denied(identity: &str, path: &Path, errno: i32) -> Status {
+    debug!("{} may not access /{}", identity, path.display());
+    crate::server::errno_status(tonic::Code::PermissionDenied, "permission denied", errno)
+}
+
+// maps bearer tokens and client certificates to grants, read from a file with lines like
+//
+//   token:<token>      <subtree> ro|rw
+//   cert:<sha256 hex>  <subtree> ro|rw
+//
+// where the certificate is identified by the sha-256 fingerprint of its der encoding
+#[derive(Debug, Default)]
+pub struct Policy {
+    tokens: HashMap<String, Grants>,
+    certs: HashMap<String, Grants>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Policy::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut tokens: HashMap<String, (String, Vec<Rule>)> = HashMap::new();
+        let mut certs: HashMap<String, (String, Vec<Rule>)> = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [identity, subtree, access] = fields[..] else {
+                return Err(format!(
+                    "line {}: expected <kind>:<id> <subtree> ro|rw",
+                    number + 1
+                ));
+            };
+
+            let writable = match access {
+                "ro" => false,
+                "rw" => true,
+                _ => return Err(format!("line {}: unknown access '{}'", number + 1, access)),
+            };
+            let subtree = subtree_path(subtree)
+                .ok_or_else(|| format!("line {}: invalid subtree '{}'", number + 1, subtree))?;
+            let rule = Rule { subtree, writable };
+
+            // tokens are secret, so they are named after the line they were first seen on
+            let (table, key, name) = match identity.split_once(':') {
+                Some(("token", token)) if !token.is_empty() => (
+                    &mut tokens,
+                    token.to_string(),
+                    format!("token@{}", number + 1),
+                ),
+                Some(("cert", fingerprint)) => {
+                    let fingerprint = fingerprint.replace(':', "").to_ascii_lowercase();
+                    if fingerprint.len() != 64
+                        || !fingerprint.chars().all(|c| c.is_ascii_hexdigit())
+                    {
+                        return Err(format!(
+                            "line {}: cert fingerprint must be a sha-256 in hex",
+                            number + 1
+                        ));
+                    }
+                    (
+                        &mut certs,
+                        fingerprint.clone(),
+                        format!("cert:{}", fingerprint),
+                    )
+                }
+                _ => {
+                    return Err(format!(
+                        "line {}: unknown identity '{}', expected token:<token> or cert:<sha256>",
+                        number + 1,
+                        identity
+                    ))
+                }
+            };
+            table
+                .entry(key)
+                .or_insert_with(|| (name, Vec::new()))
+                .1
+                .push(rule);
+        }
+
+        let grants = |table: HashMap<String, (String, Vec<Rule>)>| {
+            table
+                .into_iter()
+                .map(|(key, (name, rules))| {
+                    let grants = Grants {
+                        identity: name.into(),
+                        rules: rules.into(),
+                    };
+                    (key, grants)
+                })
+                .collect()
+        };
+        Ok(Policy {
+            tokens: grants(tokens),
+            certs: grants(certs),
+        })
+    }
+
+    // a bearer token is preferred over the client certificate
+    pub fn authenticate<T>(&self, request: &Request<T>) -> Result<Grants, Status> {
+        if let Some(authorization) = request.metadata().get(AUTHORIZATION_METADATA_KEY) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|authorization| authorization.strip_prefix("Bearer "));
+            return match token.and_then(|token| self.tokens.get(token)) {
+                Some(grants) => Ok(grants.clone()),
+                None => Err(unauthenticated("unknown bearer token")),
+            };
+        }
+
+        let certs = request.peer_certs();
+        let fingerprint = certs.as_ref().and_then(|certs| certs.first()).map(|cert| {
+            let digest = ring::digest::digest(&ring::digest::SHA256, cert.get_ref());
+            digest
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        });
+        match fingerprint {
+            Some(fingerprint) => match self.certs.get(&fingerprint) {
+                Some(grants) => Ok(grants.clone()),
+                None => Err(unauthenticated(&format!(
+                    "unknown client certificate {}",
+                    fingerprint
+                ))),
+            },
+            None => Err(unauthenticated("no bearer token or client certificate")),
+        }
+    }
+}
+
+fn unauthenticated(msg: &str) -> Status {
+    debug!("rejecting request: {}", msg);
+    Status::unauthenticated(msg)
+}
+
+// the subtree relative to the export root, "/" is the whole export
+fn subtree_path(subtree: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(subtree).components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    subtree.starts_with('/').then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ERRNO_METADATA_KEY;
+
+    const POLICY: &str = "
+        # whole export read-only, one project writable
+        token:reader  /                ro
+        token:writer  /                ro
+        token:writer  /projects/a      rw
+        cert:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89 /projects/b rw
+    ";
+
+    fn check(grants: &Grants, path: &str, access: Access) -> Option<i32> {
+        let status = grants.check(Path::new(path), access).err()?;
+        status
+            .metadata()
+            .get(ERRNO_METADATA_KEY)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    #[test]
+    fn most_specific_subtree_decides() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let reader = &policy.tokens["reader"];
+        let writer = &policy.tokens["writer"];
+        let cert =
+            &policy.certs["abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789"];
+
+        assert_eq!(check(reader, "projects/a/file", Access::Read), None);
+        assert_eq!(
+            check(reader, "projects/a/file", Access::Write),
+            Some(libc::EROFS)
+        );
+        assert_eq!(check(writer, "projects/a/file", Access::Write), None);
+        assert_eq!(
+            check(writer, "projects/ab", Access::Write),
+            Some(libc::EROFS)
+        );
+
+        // only the directories leading to a subtree can be looked up
+        assert_eq!(check(cert, "", Access::Lookup), None);
+        assert_eq!(check(cert, "projects", Access::Lookup), None);
+        assert_eq!(check(cert, "projects", Access::Read), Some(libc::EACCES));
+        assert_eq!(
+            check(cert, "projects/a", Access::Lookup),
+            Some(libc::EACCES)
+        );
+        assert_eq!(check(cert, "projects/b/file", Access::Write), None);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        for policy in [
+            "token:x / rx",
+            "token:x projects ro",
+            "token:x /../etc ro",
+            "user:x / ro",
+            "cert:abcd / ro",
+            "token:x /",
+        ] {
+            assert!(Policy::parse(policy).is_err(), "{}", policy);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+
+// file data is cached and fetched in blocks of this size
+pub const BLOCK_SIZE: u64 = 128 * 1024;
+
+// what the cached blocks of a file were read from, compared again when it is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validator {
+    pub mtime: (i64, u32),
+    pub size: u64,
+}
+
+// blocks of file data keyed by (inode, block index), dropping the least recently
+// used blocks once more than `capacity` bytes are cached
+#[derive(Debug)]
+pub struct BlockCache {
+    blocks: BTreeMap<(u64, u64), (Bytes, u64)>,
+    // last use of each block, oldest first
+    lru: BTreeMap<u64, (u64, u64)>,
+    validators: HashMap<u64, Validator>,
+    used: usize,
+    capacity: usize,
+    tick: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            validators: HashMap::new(),
+            used: 0,
+            capacity,
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, inode: u64, block: u64) -> Option<Bytes> {
+        let (data, last_use) = self.blocks.get_mut(&(inode, block))?;
+        self.lru.remove(last_use);
+        self.tick += 1;
+        *last_use = self.tick;
+        self.lru.insert(self.tick, (inode, block));
+        Some(data.clone())
+    }
+
+    pub fn insert(&mut self, inode: u64, block: u64, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+        self.remove(inode, block);
+
+        self.tick += 1;
+        self.used += data.len();
+        self.blocks.insert((inode, block), (data, self.tick));
+        self.lru.insert(self.tick, (inode, block));
+
+        while self.used > self.capacity {
+            let Some((_, (inode, block))) = self.lru.pop_first() else {
+                break;
+            };
+            self.remove(inode, block);
+            // nothing is left to validate once every block of a file is gone
+            if self
+                .blocks
+                .range((inode, 0)..=(inode, u64::MAX))
+                .next()
+                .is_none()
+            {
+                self.validators.remove(&inode);
+            }
+        }
+    }
+
+    // known size of the file, so that nothing is read ahead past its end
+    pub fn size(&self, inode: u64) -> Option<u64> {
+        self.validators.get(&inode).map(|validator| validator.size)
+    }
+
+    // version of the file that blocks read now belong to, none once it was written to
+    pub fn validator(&self, inode: u64) -> Option<Validator> {
+        self.validators.get(&inode).copied()
+    }
+
+    // drops the cached blocks unless they were read from the same version of the file,
+    // returns whether they were kept
+    pub fn validate(&mut self, inode: u64, validator: Validator) -> bool {
+        if self.validators.insert(inode, validator) == Some(validator) {
+            return true;
+        }
+        self.remove_blocks(inode);
+        false
+    }
+
+    pub fn invalidate(&mut self, inode: u64) {
+        self.validators.remove(&inode);
+        self.remove_blocks(inode);
+    }
+
+    fn remove_blocks(&mut self, inode: u64) {
+        let blocks: Vec<_> = self
+            .blocks
+            .range((inode, 0)..=(inode, u64::MAX))
+            .map(|(&(_, block), _)| block)
+            .collect();
+        for block in blocks {
+            self.remove(inode, block);
+        }
+    }
+
+    fn remove(&mut self, inode: u64, block: u64) {
+        if let Some((data, last_use)) = self.blocks.remove(&(inode, block)) {
+            self.lru.remove(&last_use);
+            self.used -= data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(byte: u8, len: usize) -> Bytes {
+        Bytes::from(vec![byte; len])
+    }
+
+    #[test]
+    fn least_recently_used_blocks_go_first() {
+        let mut block_cache = BlockCache::new(10);
+        block_cache.insert(1, 0, block(0, 4));
+        block_cache.insert(1, 1, block(1, 4));
+        // using the older block makes the newer one the least recently used
+        assert!(block_cache.get(1, 0).is_some());
+        block_cache.insert(2, 0, block(2, 4));
+
+        assert_eq!(block_cache.get(1, 1), None);
+        assert_eq!(block_cache.get(1, 0), Some(block(0, 4)));
+        assert_eq!(block_cache.get(2, 0), Some(block(2, 4)));
+        block_cache.insert(2, 1, block(3, 4));
+        assert_eq!(block_cache.get(1, 0), None);
+    }
+
+    #[test]
+    fn cached_bytes_stay_within_capacity() {
+        let mut block_cache = BlockCache::new(10);
+        let validator = Validator {
+            mtime: (1, 0),
+            size: 6,
+        };
+        block_cache.validate(1, validator);
+        block_cache.insert(1, 0, block(0, 6));
+        // replacing a block counts only the new data
+        block_cache.insert(1, 0, block(1, 3));
+        assert_eq!(block_cache.used, 3);
+        // a block larger than the whole cache is not kept
+        block_cache.insert(1, 1, block(2, 11));
+        assert_eq!(block_cache.used, 3);
+
+        // once the last block of a file is evicted, so is what it was read from
+        block_cache.insert(2, 0, block(3, 10));
+        assert_eq!(block_cache.used, 10);
+        assert_eq!(block_cache.size(1), None);
+        block_cache.invalidate(2);
+        assert_eq!(block_cache.used, 0);
+        assert!(block_cache.blocks.is_empty() && block_cache.lru.is_empty());
+    }
+}
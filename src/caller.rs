@@ -0,0 +1,115 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+// the client sends who made a request in these metadata entries,
+// groups as a comma separated list
+const UID_METADATA_KEY: &str = "uid";
+const GID_METADATA_KEY: &str = "gid";
+const GROUPS_METADATA_KEY: &str = "groups";
+
+// permission bits checked by `Caller::permits`
+pub const MAY_READ: u32 = 0o4;
+pub const MAY_WRITE: u32 = 0o2;
+pub const MAY_EXEC: u32 = 0o1;
+
+// the process on the client side that a request is made for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    // supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Caller {
+    // the kernel only tells the uid and gid, the supplementary groups are read from /proc
+    pub fn of_process(uid: u32, gid: u32, pid: u32) -> Self {
+        let groups = fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| {
+                let groups = status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Groups:"))?;
+                Some(
+                    groups
+                        .split_whitespace()
+                        .filter_map(|group| group.parse().ok())
+                        .collect(),
+                )
+            })
+            .unwrap_or_default();
+        Caller { uid, gid, groups }
+    }
+
+    pub fn insert_into(&self, metadata: &mut MetadataMap) {
+        let groups: Vec<_> = self.groups.iter().map(|group| group.to_string()).collect();
+        metadata.insert(UID_METADATA_KEY, self.uid.into());
+        metadata.insert(GID_METADATA_KEY, self.gid.into());
+        if let Ok(groups) = groups.join(",").parse() {
+            metadata.insert(GROUPS_METADATA_KEY, groups);
+        }
+    }
+
+    // none if the client did not say who the request is for
+    pub fn from_metadata(metadata: &MetadataMap) -> Result<Option<Self>, Status> {
+        let Some(uid) = metadata.get(UID_METADATA_KEY) else {
+            return Ok(None);
+        };
+        let invalid = |key| Status::invalid_argument(format!("invalid {} metadata", key));
+        let parse = |value: &tonic::metadata::AsciiMetadataValue, key| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| invalid(key))
+        };
+
+        let uid = parse(uid, UID_METADATA_KEY)?;
+        let gid = match metadata.get(GID_METADATA_KEY) {
+            Some(gid) => parse(gid, GID_METADATA_KEY)?,
+            None => return Err(invalid(GID_METADATA_KEY)),
+        };
+        let groups = match metadata.get(GROUPS_METADATA_KEY) {
+            Some(groups) => groups
+                .to_str()
+                .map_err(|_| invalid(GROUPS_METADATA_KEY))?
+                .split(',')
+                .filter(|group| !group.is_empty())
+                .map(|group| group.parse().map_err(|_| invalid(GROUPS_METADATA_KEY)))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(Some(Caller { uid, gid, groups }))
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    pub fn owns(&self, metadata: &fs::Metadata) -> bool {
+        self.is_root() || self.uid == metadata.uid()
+    }
+
+    // the usual owner, group, other check of `mask` against the mode of the file;
+    // root may read and write anything, and execute anything that is executable at all
+    pub fn permits(&self, metadata: &fs::Metadata, mask: u32) -> bool {
+        let mode = metadata.mode();
+        if self.is_root() {
+            return mask & MAY_EXEC == 0 || metadata.is_dir() || mode & 0o111 != 0;
+        }
+        let bits = if self.uid == metadata.uid() {
+            mode >> 6
+        } else if self.in_group(metadata.gid()) {
+            mode >> 3
+        } else {
+            mode
+        };
+        bits & mask == mask
+    }
+}
@@ -1,273 +1,1219 @@
+use crate::block_cache::{BlockCache, Validator, BLOCK_SIZE};
+use crate::caller::Caller;
+use crate::connection::{Connection, Op, RetryPolicy};
+use crate::disk_cache::DiskCache;
+use crate::id_map::IdMap;
+use crate::server::{ERRNO_METADATA_KEY, HANDLE_LEASE};
+use crate::tls::ClientTls;
+use bytes::{Bytes, BytesMut};
 use fuse3::raw::prelude::*;
-use fuse3::Result;
+use fuse3::{Errno, Result};
+use futures_util::future::{self, BoxFuture, FutureExt, Shared};
 use futures_util::stream;
 use futures_util::stream::Iter;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
-use rpc_fs::rpc_fs_client::RpcFsClient;
 use rpc_fs::*;
-use std::collections::BTreeMap;
-use std::iter::Skip;
-use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::IntoIter;
-use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
 
 pub mod rpc_fs {
     tonic::include_proto!("rpc_fs");
 }
 
+#[derive(Debug)]
+pub struct ClientOptions {
+    // report every file as owned by this uid/gid instead of the server-side owner,
+    // which takes precedence over `id_map`
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // how long attributes, names and missing names are cached, here and by the kernel
+    pub attr_ttl: Duration,
+    pub entry_ttl: Duration,
+    pub negative_ttl: Duration,
+    // bytes of file data cached in memory, 0 turns the cache off
+    pub cache_size: usize,
+    // blocks read ahead once reads on a file handle are sequential
+    pub readahead: u64,
+    // directory to keep file data in across mounts, and how many bytes it may hold
+    pub disk_cache: Option<PathBuf>,
+    pub disk_cache_size: u64,
+    // how calls are retried while the server cannot be reached
+    pub retry: RetryPolicy,
+    // certificates for an https:// server
+    pub tls: Option<ClientTls>,
+    // bearer token for servers with a policy
+    pub token: Option<String>,
+    // translates users and groups between this host and the server
+    pub id_map: IdMap,
+    // export of the server to use, none for its default export
+    pub export: Option<String>,
+}
+
+// inode numbers of files the kernel holds no inode for are dropped once there are
+// this many of them, after which those files may show a different number
+const INODE_NUMBERS_PRUNE_LEN: usize = 1 << 20;
+
+// a lookup replied this recently may not have reached the kernel before it sent a
+// batch forget, which then did not count it
+const FORGET_RACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct InodeEntry {
+    // every name the inode was looked up by, the latest first, none once the file
+    // was removed while the kernel still holds the inode
+    paths: Vec<String>,
+    // directory the inode was last looked up in, the root is its own parent
+    parent: u64,
+    // (st_dev, st_ino) of the file on the server
+    server_id: (u64, u64),
+    // how many times the inode has been handed to the kernel, dropped again by forget
+    nlookup: u64,
+    // when the inode was last handed to the kernel
+    looked_up: Instant,
+    // readdirplus entries that may still be handed to the kernel
+    held: u64,
+    // file handles the inode is open by, which still work once it has no path
+    handles: Vec<u64>,
+}
+
+// the client allocates its own inode numbers, so that files on different server
+// filesystems do not collide and hard links share one inode
+#[derive(Debug)]
+struct InodeTable {
+    // inodes held by the kernel
+    entries: BTreeMap<u64, InodeEntry>,
+    // numbers of every file seen, including those only listed by readdir, so that
+    // a file keeps its number while the kernel looks it up and forgets it again
+    inodes: HashMap<(u64, u64), u64>,
+    next_inode: u64,
+}
+
+impl InodeTable {
+    fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        // "/" is resolved relative to the export root by the server
+        entries.insert(
+            1,
+            InodeEntry {
+                paths: vec![String::from("/")],
+                parent: 1,
+                server_id: (0, 0),
+                nlookup: 0,
+                looked_up: Instant::now(),
+                held: 0,
+                handles: Vec::new(),
+            },
+        );
+        InodeTable {
+            entries,
+            inodes: HashMap::new(),
+            next_inode: 2,
+        }
+    }
+
+    fn path(&self, inode: u64) -> Option<String> {
+        self.entries.get(&inode)?.paths.first().cloned()
+    }
+
+    fn parent(&self, inode: u64) -> Option<u64> {
+        self.entries.get(&inode).map(|entry| entry.parent)
+    }
+
+    fn server_id(&self, inode: u64) -> Option<(u64, u64)> {
+        self.entries.get(&inode).map(|entry| entry.server_id)
+    }
+
+    // the client inode number of a server file, without the kernel holding it
+    fn number(&mut self, dev: u64, ino: u64) -> u64 {
+        if let Some(&inode) = self.inodes.get(&(dev, ino)) {
+            return inode;
+        }
+        if self.inodes.len() >= INODE_NUMBERS_PRUNE_LEN {
+            let entries = &self.entries;
+            self.inodes.retain(|_, inode| entries.contains_key(inode));
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.inodes.insert((dev, ino), inode);
+        inode
+    }
+
+    fn assign(&mut self, attr: &Attr, path: String, parent: u64) -> (u64, &mut InodeEntry) {
+        let server_id = (attr.dev, attr.inode);
+        let inode = self.number(attr.dev, attr.inode);
+        debug!("caching: inode #{}, path = {}", inode, path);
+
+        let entry = self.entries.entry(inode).or_insert(InodeEntry {
+            paths: Vec::new(),
+            parent,
+            server_id,
+            nlookup: 0,
+            looked_up: Instant::now(),
+            held: 0,
+            handles: Vec::new(),
+        });
+        entry.paths.retain(|known| *known != path);
+        entry.paths.insert(0, path);
+        entry.parent = parent;
+        (inode, entry)
+    }
+
+    // records an entry handed to the kernel, which holds one more reference to it
+    fn lookup(&mut self, attr: &Attr, path: String, parent: u64) -> u64 {
+        let (inode, entry) = self.assign(attr, path, parent);
+        entry.nlookup += 1;
+        entry.looked_up = Instant::now();
+        inode
+    }
+
+    fn hold(&mut self, attr: &Attr, path: String, parent: u64) -> u64 {
+        let (inode, entry) = self.assign(attr, path, parent);
+        entry.held += 1;
+        inode
+    }
+
+    fn handle(&self, inode: u64) -> Option<u64> {
+        self.entries.get(&inode)?.handles.first().copied()
+    }
+
+    fn open_handle(&mut self, inode: u64, fh: u64) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            entry.handles.push(fh);
+        }
+    }
+
+    fn open_handles(&self) -> Vec<u64> {
+        let entries = self.entries.values();
+        entries
+            .flat_map(|entry| entry.handles.iter().copied())
+            .collect()
+    }
+
+    fn release_handle(&mut self, inode: u64, fh: u64) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            entry.handles.retain(|&handle| handle != fh);
+        }
+    }
+
+    // a held entry made it to the kernel
+    fn confirm(&mut self, inode: u64) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            entry.held -= 1;
+            entry.nlookup += 1;
+            entry.looked_up = Instant::now();
+        }
+    }
+
+    fn unhold(&mut self, inode: u64) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            entry.held -= 1;
+            self.evict_unused(inode);
+        }
+    }
+
+    fn forget(&mut self, inode: u64, nlookup: u64) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            entry.nlookup = entry.nlookup.saturating_sub(nlookup);
+            self.evict_unused(inode);
+        }
+    }
+
+    // a batch forget drops every reference the kernel counted, which may miss a lookup
+    // replied at the same time, so one is kept for that until the inode is forgotten
+    // again; if the kernel did count it, the entry stays until it is next forgotten
+    fn forget_all(&mut self, inode: u64, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&inode) {
+            if now.saturating_duration_since(entry.looked_up) < FORGET_RACE {
+                entry.nlookup = entry.nlookup.min(1);
+            } else {
+                entry.nlookup = 0;
+            }
+            self.evict_unused(inode);
+        }
+    }
+
+    fn evict_unused(&mut self, inode: u64) {
+        // the root is never looked up, so it is kept regardless
+        if inode == 1 {
+            return;
+        }
+        if let Some(entry) = self.entries.get(&inode) {
+            if entry.nlookup == 0 && entry.held == 0 {
+                debug!("evicting: inode #{}, paths = {:?}", inode, entry.paths);
+                self.entries.remove(&inode);
+            }
+        }
+    }
+
+    // a removed file's server inode may be reused for a new file, which must not get
+    // the client inode the kernel still holds for the old one
+    fn forget_server_id(&mut self, inode: u64, server_id: (u64, u64)) {
+        if self.inodes.get(&server_id) == Some(&inode) {
+            self.inodes.remove(&server_id);
+        }
+    }
+
+    // other hard links keep the inode reachable, without any it stays until the kernel
+    // forgets it, but can no longer be found by path
+    fn remove_path(&mut self, path: &str) {
+        let mut removed = Vec::new();
+        for (&inode, entry) in self.entries.iter_mut() {
+            if inode == 1 || !entry.paths.iter().any(|known| known == path) {
+                continue;
+            }
+            entry.paths.retain(|known| known != path);
+            if entry.paths.is_empty() {
+                removed.push((inode, entry.server_id));
+            }
+        }
+        for (inode, server_id) in removed {
+            debug!("detaching: inode #{}, path = {}", inode, path);
+            self.forget_server_id(inode, server_id);
+        }
+    }
+
+    // moves `from` and everything under it to `to`
+    fn rename_path(&mut self, from: &str, to: &str, new_parent: u64) {
+        // like rename(2), renaming a file to another of its own names does nothing
+        let linked = self.entries.values().any(|entry| {
+            entry.paths.iter().any(|path| path == from) && entry.paths.iter().any(|path| path == to)
+        });
+        if linked {
+            return;
+        }
+
+        self.remove_path(to);
+        for entry in self.entries.values_mut() {
+            if entry.paths.iter().any(|path| path == from) {
+                entry.parent = new_parent;
+            }
+            for path in &mut entry.paths {
+                if let Ok(rest) = Path::new(path.as_str()).strip_prefix(from) {
+                    *path = if rest.as_os_str().is_empty() {
+                        to.to_string()
+                    } else {
+                        Path::new(to).join(rest).to_string_lossy().into_owned()
+                    };
+                }
+            }
+        }
+    }
+}
+
+// expired attributes are dropped once the cache grows past this many paths
+const ATTR_CACHE_PRUNE_LEN: usize = 4096;
+
+// server attributes by path, None for paths known not to exist
+#[derive(Debug)]
+struct AttrCache {
+    entries: HashMap<String, (Instant, Option<Attr>)>,
+    max_ttl: Duration,
+}
+
+impl AttrCache {
+    fn new(max_ttl: Duration) -> Self {
+        AttrCache {
+            entries: HashMap::new(),
+            max_ttl,
+        }
+    }
+
+    // Some(None) is a cached negative lookup
+    fn get(&self, path: &str, ttl: Duration, negative_ttl: Duration) -> Option<Option<Attr>> {
+        let (fetched, attr) = self.entries.get(path)?;
+        let ttl = if attr.is_some() { ttl } else { negative_ttl };
+        (fetched.elapsed() < ttl).then(|| attr.clone())
+    }
+
+    fn insert(&mut self, path: String, attr: Option<Attr>) {
+        if self.entries.len() >= ATTR_CACHE_PRUNE_LEN {
+            let max_ttl = self.max_ttl;
+            self.entries
+                .retain(|_, (fetched, _)| fetched.elapsed() < max_ttl);
+        }
+        self.entries.insert(path, (Instant::now(), attr));
+    }
+
+    fn invalidate(&mut self, path: &str) {
+        self.entries.remove(path);
+    }
+
+    // drops `path` and everything under it
+    fn invalidate_tree(&mut self, path: &str) {
+        self.entries
+            .retain(|cached, _| !Path::new(cached).starts_with(path));
+    }
+}
+
+type BlockFetch = Shared<BoxFuture<'static, Result<Bytes>>>;
+
+// blocks being read from the server, shared between reads and readahead, with the
+// handle each one reads through
+#[derive(Default)]
+struct InFlight {
+    fetches: BTreeMap<(u64, u64), (u64, u64, BlockFetch)>,
+    // readahead started on each handle
+    readahead: HashMap<u64, Vec<AbortHandle>>,
+    next_id: u64,
+}
+
+impl InFlight {
+    // fetches still running are not cached once they are dropped from here
+    fn forget(&mut self, inode: u64) {
+        self.fetches
+            .retain(|&(fetch_inode, _), _| fetch_inode != inode);
+    }
+
+    // stops reading through a handle that is being released, so that nobody else
+    // joins a read the server is about to fail
+    fn forget_handle(&mut self, fh: u64) {
+        self.fetches.retain(|_, (_, fetch_fh, _)| *fetch_fh != fh);
+        for readahead in self.readahead.remove(&fh).unwrap_or_default() {
+            readahead.abort();
+        }
+    }
+}
+
 pub struct GrpcFsClient {
-    inode_map: RwLock<BTreeMap<u64, String>>,
-    #[allow(dead_code)]
-    address: String,
-    client: RpcFsClient<tonic::transport::Channel>,
+    inode_table: Arc<RwLock<InodeTable>>,
+    attr_cache: Mutex<AttrCache>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    disk_cache: Option<Arc<Mutex<DiskCache>>>,
+    in_flight: Arc<Mutex<InFlight>>,
+    // where the last read on each file handle ended
+    read_offsets: Mutex<HashMap<u64, u64>>,
+    connection: Connection,
+    options: ClientOptions,
 }
 
 impl GrpcFsClient {
-    pub async fn new(address: String) -> Self {
-        let client = RpcFsClient::connect(address.clone()).await;
-        match client {
-            Ok(_) => {
-                info!("successfully connected to server");
+    pub fn new(
+        address: String,
+        options: ClientOptions,
+    ) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let connection = Connection::new(
+            &address,
+            options.retry,
+            options.tls.as_ref(),
+            options.token.as_deref(),
+            options.export.as_deref(),
+        )?;
+
+        // each export of a server keeps its own cached files
+        let cache_name = match &options.export {
+            Some(export) => format!("{}/{}", address, export),
+            None => address.clone(),
+        };
+        let disk_cache = options.disk_cache.as_ref().and_then(|dir| {
+            match DiskCache::open(dir, &cache_name, options.disk_cache_size) {
+                Ok(disk_cache) => Some(Arc::new(Mutex::new(disk_cache))),
+                Err(e) => {
+                    error!("failed to open disk cache {}: {}", dir.display(), e);
+                    None
+                }
+            }
+        });
+
+        let max_ttl = options
+            .attr_ttl
+            .max(options.entry_ttl)
+            .max(options.negative_ttl);
+        Ok(GrpcFsClient {
+            inode_table: Arc::new(RwLock::new(InodeTable::new())),
+            attr_cache: Mutex::new(AttrCache::new(max_ttl)),
+            block_cache: Arc::new(Mutex::new(BlockCache::new(options.cache_size))),
+            disk_cache,
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
+            read_offsets: Mutex::new(HashMap::new()),
+            connection,
+            options,
+        })
+    }
+
+    // the exports of the server this client may use, asked once whatever the retry policy
+    pub async fn list_exports(&self) -> std::result::Result<Vec<ExportInfo>, tonic::Status> {
+        let reply = self
+            .connection
+            .call_once(
+                None,
+                ListExportsRequest {},
+                |mut client, request| async move { client.list_exports(request).await },
+            )
+            .await?;
+        Ok(reply.into_inner().exports)
+    }
+
+    // reaches the root of the export, which the server only fails to find or let us into
+    // if every other operation would fail the same way; asked once, so that a server that
+    // is down does not hold up mounting
+    pub async fn check_export(&self) -> std::result::Result<(), tonic::Status> {
+        let request = GetAttrRequest {
+            path: String::from("/"),
+            fh: None,
+        };
+        self.connection
+            .call_once(None, request, |mut client, request| async move {
+                client.get_attr(request).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn lookup_inode(&self, attr: &Attr, path: String, parent: u64) -> u64 {
+        self.inode_table.write().unwrap().lookup(attr, path, parent)
+    }
+
+    fn forget_inode(&self, inode: u64, nlookup: u64) {
+        self.inode_table.write().unwrap().forget(inode, nlookup);
+    }
+
+    fn remove_path(&self, path: &str) {
+        self.inode_table.write().unwrap().remove_path(path);
+    }
+
+    fn rename_path(&self, from: &str, to: &str, new_parent: u64) {
+        self.inode_table
+            .write()
+            .unwrap()
+            .rename_path(from, to, new_parent);
+    }
+
+    fn cached_attr(&self, path: &str, ttl: Duration) -> Option<Option<Attr>> {
+        let attr_cache = self.attr_cache.lock().unwrap();
+        attr_cache.get(path, ttl, self.options.negative_ttl)
+    }
+
+    fn cache_attr(&self, path: &str, attr: Option<Attr>) {
+        self.attr_cache
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), attr);
+    }
+
+    fn invalidate_attr(&self, path: &str) {
+        self.attr_cache.lock().unwrap().invalidate(path);
+    }
+
+    fn invalidate_attr_tree(&self, path: &str) {
+        self.attr_cache.lock().unwrap().invalidate_tree(path);
+    }
+
+    async fn read_blocks(&self, inode: u64, fh: u64, offset: u64, size: u32) -> Result<ReplyData> {
+        let end = offset + u64::from(size);
+        let first = offset / BLOCK_SIZE;
+        let last = end.div_ceil(BLOCK_SIZE);
+
+        // read ahead if this read carries on where the last one on the handle ended
+        let sequential = self.read_offsets.lock().unwrap().insert(fh, end) == Some(offset);
+        if sequential {
+            let blocks = self.block_cache.lock().unwrap().size(inode);
+            let blocks = blocks.map_or(u64::MAX, |size| size.div_ceil(BLOCK_SIZE));
+            let readahead: Vec<_> = (last..last.saturating_add(self.options.readahead).min(blocks))
+                .map(|block| tokio::spawn(self.block(inode, fh, block).0).abort_handle())
+                .collect();
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let started = in_flight.readahead.entry(fh).or_default();
+            started.retain(|readahead| !readahead.is_finished());
+            started.extend(readahead);
+        }
+
+        let fetches = (first..last).map(|block| self.read_block(inode, fh, block));
+        let blocks = future::join_all(fetches).await;
+
+        let mut data = BytesMut::with_capacity(size as usize);
+        for (block, fetched) in (first..last).zip(blocks) {
+            let fetched = fetched?;
+            let block_start = block * BLOCK_SIZE;
+            let from = (offset.saturating_sub(block_start) as usize).min(fetched.len());
+            let to = ((end - block_start) as usize).min(fetched.len());
+            data.extend_from_slice(&fetched[from..to]);
+            // a short block is the end of the file
+            if (fetched.len() as u64) < BLOCK_SIZE {
+                break;
             }
-            Err(e) => panic!("failed to connect to server: {}", e),
         }
+        Ok(ReplyData {
+            data: data.freeze(),
+        })
+    }
 
-        let c = GrpcFsClient {
-            inode_map: RwLock::new(BTreeMap::new()),
-            address,
-            client: client.unwrap(),
+    // a block read through `fh`, read again through it if a read shared through another
+    // handle failed, as that handle may have been released meanwhile
+    async fn read_block(&self, inode: u64, fh: u64, block: u64) -> Result<Bytes> {
+        let (fetch, fetch_fh) = self.block(inode, fh, block);
+        match fetch.await {
+            Err(_) if fetch_fh != fh => self.block(inode, fh, block).0.await,
+            fetched => fetched,
+        }
+    }
+
+    // the cached block, or a read of it shared with everyone else waiting for it, with
+    // the handle it reads through
+    fn block(&self, inode: u64, fh: u64, block: u64) -> (BlockFetch, u64) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let mut block_cache = self.block_cache.lock().unwrap();
+        if let Some(data) = block_cache.get(inode, block) {
+            return (future::ready(Ok(data)).boxed().shared(), fh);
+        }
+        let validator = block_cache.validator(inode);
+        drop(block_cache);
+        if let Some((_, fetch_fh, fetch)) = in_flight.fetches.get(&(inode, block)) {
+            return (fetch.clone(), *fetch_fh);
+        }
+
+        let server_id = self.inode_table.read().unwrap().server_id(inode);
+        // the disk only holds blocks of a known version of the file
+        let disk_cache = match (&self.disk_cache, server_id, validator) {
+            (Some(disk_cache), Some(server_id), Some(validator)) => {
+                Some((disk_cache.clone(), server_id, validator))
+            }
+            _ => None,
         };
-        c.inode_map.write().await.insert(1, String::from("/"));
 
-        c
+        in_flight.next_id += 1;
+        let id = in_flight.next_id;
+        let connection = self.connection.clone();
+        let block_cache = self.block_cache.clone();
+        let fetches = self.in_flight.clone();
+        let fetch = async move {
+            // the disk is read off the runtime, as part of the fetch so that it is shared too
+            let cached = match disk_cache.clone() {
+                Some((disk_cache, server_id, _)) => tokio::task::spawn_blocking(move || {
+                    disk_cache.lock().unwrap().get(server_id, block)
+                })
+                .await
+                .unwrap_or(None),
+                None => None,
+            };
+            let from_disk = cached.is_some();
+            let request = ReadRequest {
+                fh,
+                offset: block * BLOCK_SIZE,
+                size: BLOCK_SIZE as i64,
+            };
+            let data = match cached {
+                Some(data) => Ok(data),
+                None => match connection
+                    .call(
+                        Op::Idempotent,
+                        None,
+                        request,
+                        |mut client, request| async move { client.read(request).await },
+                    )
+                    .await
+                {
+                    Ok(response) => Ok(Bytes::from(response.into_inner().data)),
+                    Err(e) => {
+                        warn!("failed to read block {} of inode {}: {}", block, inode, e);
+                        Err(errno(&e))
+                    }
+                },
+            };
+
+            // the fetch is gone from the table if the file changed in the meantime
+            let mut in_flight = fetches.lock().unwrap();
+            let current = matches!(
+                in_flight.fetches.get(&(inode, block)),
+                Some((fetch_id, _, _)) if *fetch_id == id
+            );
+            if current {
+                in_flight.fetches.remove(&(inode, block));
+                if let Ok(data) = &data {
+                    block_cache
+                        .lock()
+                        .unwrap()
+                        .insert(inode, block, data.clone());
+                }
+            }
+            drop(in_flight);
+
+            if let (true, false, Ok(data), Some((disk_cache, server_id, validator))) =
+                (current, from_disk, &data, disk_cache)
+            {
+                let data = data.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut disk_cache = disk_cache.lock().unwrap();
+                    disk_cache.insert(server_id, block, validator, &data)
+                });
+            }
+            data
+        }
+        .boxed()
+        .shared();
+        in_flight
+            .fetches
+            .insert((inode, block), (id, fh, fetch.clone()));
+        (fetch, fh)
     }
 
-    async fn append_inode(&self, inode: u64, path: String) {
-        if inode == 1 {
-            warn!("inode number 1 is reserved: path \"{}\"", path);
+    // drops cached data of a file that was opened, if it changed on the server
+    async fn validate_blocks(&self, inode: u64, attr: &Attr) {
+        let validator = Validator {
+            mtime: attr
+                .mtime
+                .as_ref()
+                .map_or((0, 0), |mtime| (mtime.sec, mtime.nsec)),
+            size: attr.size,
+        };
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if !self.block_cache.lock().unwrap().validate(inode, validator) {
+                in_flight.forget(inode);
+            }
+        }
+
+        // the disk is checked off the runtime once the locks are released, blocks read in
+        // the meantime only make it to the disk under the version they were read from
+        let Some(disk_cache) = self.disk_cache.clone() else {
             return;
+        };
+        let server_id = (attr.dev, attr.inode);
+        let valid = tokio::task::spawn_blocking(move || {
+            disk_cache.lock().unwrap().validate(server_id, validator)
+        })
+        .await;
+        if !valid.unwrap_or(false) {
+            self.in_flight.lock().unwrap().forget(inode);
+        }
+    }
+
+    async fn invalidate_blocks(&self, inode: u64) {
+        let server_id = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            self.block_cache.lock().unwrap().invalidate(inode);
+            in_flight.forget(inode);
+            self.inode_table.read().unwrap().server_id(inode)
+        };
+
+        if let (Some(disk_cache), Some(server_id)) = (self.disk_cache.clone(), server_id) {
+            let invalidated = tokio::task::spawn_blocking(move || {
+                disk_cache.lock().unwrap().invalidate(server_id)
+            });
+            let _ = invalidated.await;
         }
-        debug!("caching: inode #{}, path = {}", inode, path);
-        self.inode_map.write().await.insert(inode, path);
     }
 
-    async fn get_path(&self, inode: u64) -> Option<String> {
-        if let Some(path) = self.inode_map.read().await.get(&inode) {
-            return Some(path.clone());
+    // crtime is only reported to the kernel on macOS, so it is not used here
+    fn file_attr(&self, inode: u64, attr: Attr) -> FileAttr {
+        FileAttr {
+            ino: inode,
+            generation: 0,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: timestamp(attr.atime),
+            mtime: timestamp(attr.mtime),
+            ctime: timestamp(attr.ctime),
+            kind: file_type(attr.kind),
+            perm: attr.permission as u16,
+            nlink: attr.nlink,
+            uid: self
+                .options
+                .uid
+                .unwrap_or_else(|| self.options.id_map.uid_to_client(attr.uid)),
+            gid: self
+                .options
+                .gid
+                .unwrap_or_else(|| self.options.id_map.gid_to_client(attr.gid)),
+            rdev: attr.rdev,
+            blksize: attr.blksize,
         }
-        None
+    }
+
+    // who the server checks the request against, as a user of the server host
+    fn caller(&self, req: &Request) -> Caller {
+        let caller = Caller::of_process(req.uid, req.gid, req.pid);
+        self.options.id_map.caller_to_server(caller)
+    }
+
+    fn get_path(&self, inode: u64) -> Option<String> {
+        self.inode_table.read().unwrap().path(inode)
+    }
+
+    // the handle to send with a request on an inode: the kernel's, or one the inode is
+    // open by once it has no path any more
+    fn target_handle(
+        &self,
+        inode: u64,
+        path: &Option<String>,
+        fh: Option<u64>,
+    ) -> Result<Option<u64>> {
+        if fh.is_some() || path.is_some() {
+            return Ok(fh);
+        }
+        match self.inode_table.read().unwrap().handle(inode) {
+            Some(fh) => Ok(Some(fh)),
+            None => Err(libc::ENOENT.into()),
+        }
+    }
+}
+
+// fuse3 takes one entry more than fits in a readdirplus reply and drops it, so an
+// entry is only counted as handed to the kernel once the next one is asked for
+pub struct CountedEntries {
+    entries: IntoIter<(DirectoryEntryPlus, bool)>,
+    pending: Option<u64>,
+    inode_table: Arc<RwLock<InodeTable>>,
+}
+
+impl Iterator for CountedEntries {
+    type Item = Result<DirectoryEntryPlus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(inode) = self.pending.take() {
+            self.inode_table.write().unwrap().confirm(inode);
+        }
+        let (entry, held) = self.entries.next()?;
+        if held {
+            self.pending = Some(entry.inode);
+        }
+        Some(Ok(entry))
+    }
+}
+
+impl Drop for CountedEntries {
+    fn drop(&mut self) {
+        let mut inode_table = self.inode_table.write().unwrap();
+        let unsent = self.entries.by_ref().filter(|(_, held)| *held);
+        for inode in self
+            .pending
+            .into_iter()
+            .chain(unsent.map(|(entry, _)| entry.inode))
+        {
+            inode_table.unhold(inode);
+        }
+    }
+}
+
+fn errno(status: &tonic::Status) -> Errno {
+    let errno = status
+        .metadata()
+        .get(ERRNO_METADATA_KEY)
+        .and_then(|errno| errno.to_str().ok())
+        .and_then(|errno| errno.parse().ok());
+
+    // guess from the status code if the server did not send one
+    errno
+        .unwrap_or_else(|| match status.code() {
+            tonic::Code::NotFound => libc::ENOENT,
+            tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => libc::EACCES,
+            tonic::Code::AlreadyExists => libc::EEXIST,
+            tonic::Code::InvalidArgument => libc::EINVAL,
+            tonic::Code::Unimplemented => libc::ENOSYS,
+            _ => libc::EIO,
+        })
+        .into()
+}
+
+fn file_type(kind: i32) -> fuse3::FileType {
+    match rpc_fs::FileType::try_from(kind) {
+        Ok(rpc_fs::FileType::Directory) => fuse3::FileType::Directory,
+        Ok(rpc_fs::FileType::Symlink) => fuse3::FileType::Symlink,
+        Ok(rpc_fs::FileType::Fifo) => fuse3::FileType::NamedPipe,
+        Ok(rpc_fs::FileType::Socket) => fuse3::FileType::Socket,
+        Ok(rpc_fs::FileType::CharDevice) => fuse3::FileType::CharDevice,
+        Ok(rpc_fs::FileType::BlockDevice) => fuse3::FileType::BlockDevice,
+        _ => fuse3::FileType::RegularFile,
+    }
+}
+
+fn timestamp(time: Option<rpc_fs::Timestamp>) -> fuse3::Timestamp {
+    match time {
+        Some(time) => fuse3::Timestamp::new(time.sec, time.nsec),
+        None => SystemTime::UNIX_EPOCH.into(),
     }
 }
 
+fn join_path(parent: &str, name: &OsStr) -> String {
+    Path::new(parent).join(name).to_string_lossy().into_owned()
+}
+
 // TODO: maybe use PathFileSystem
 #[async_trait::async_trait]
 impl Filesystem for GrpcFsClient {
-    type DirEntryStream = Iter<Skip<IntoIter<Result<DirectoryEntry>>>>;
-    type DirEntryPlusStream = Iter<Skip<IntoIter<Result<DirectoryEntryPlus>>>>;
+    type DirEntryStream = Iter<IntoIter<Result<DirectoryEntry>>>;
+    type DirEntryPlusStream = Iter<CountedEntries>;
 
     async fn init(&self, _req: Request) -> Result<()> {
+        // the server closes handles that go unused for its lease, which open files left
+        // alone for a while must not be taken for
+        let inode_table = Arc::downgrade(&self.inode_table);
+        let connection = self.connection.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HANDLE_LEASE / 4);
+            loop {
+                interval.tick().await;
+                let Some(inode_table) = inode_table.upgrade() else {
+                    break;
+                };
+                let fhs = inode_table.read().unwrap().open_handles();
+                if fhs.is_empty() {
+                    continue;
+                }
+                let request = RenewHandlesRequest { fhs };
+                let renewed = connection
+                    .call(
+                        Op::Idempotent,
+                        None,
+                        request,
+                        |mut client, request| async move { client.renew_handles(request).await },
+                    )
+                    .await;
+                if let Err(e) = renewed {
+                    warn!("failed to renew file handles: {}", e);
+                }
+            }
+        });
         Ok(())
     }
 
     async fn destroy(&self, _req: Request) {}
 
+    async fn forget(&self, _req: Request, inode: u64, nlookup: u64) {
+        debug!("forget: inode {}, nlookup {}", inode, nlookup);
+        self.forget_inode(inode, nlookup);
+    }
+
+    async fn batch_forget(&self, _req: Request, inodes: &[u64]) {
+        debug!("batch_forget: inodes {:?}", inodes);
+        // fuse3 does not pass the counts here, the kernel sends the inodes it dropped
+        let now = Instant::now();
+        let mut inode_table = self.inode_table.write().unwrap();
+        for &inode in inodes {
+            inode_table.forget_all(inode, now);
+        }
+    }
+
     async fn getattr(
         &self,
-        _req: Request,
+        req: Request,
         inode: u64,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _flags: u32,
     ) -> Result<ReplyAttr> {
         debug!("getattr: inode {}", inode);
-        if let Some(path) = self.get_path(inode).await {
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(GetAttrRequest { path: path.clone() });
+        let path = self.get_path(inode);
+        if let Some(Some(attr)) = path
+            .as_ref()
+            .and_then(|path| self.cached_attr(path, self.options.attr_ttl))
+        {
+            return Ok(ReplyAttr {
+                ttl: self.options.attr_ttl,
+                attr: self.file_attr(inode, attr),
+            });
+        }
+        let fh = self.target_handle(inode, &path, fh)?;
 
-            let response = client.get_attr(request).await;
-            match response {
+        let caller = self.caller(&req);
+        let request = GetAttrRequest {
+            path: path.clone().unwrap_or_default(),
+            fh,
+        };
+
+        let response = self
+            .connection
+            .call(
+                Op::Idempotent,
+                Some(&caller),
+                request,
+                |mut client, request| async move { client.get_attr(request).await },
+            )
+            .await;
+        match response {
+            Ok(response) => {
+                let attr = response.into_inner().attributes.unwrap();
+                if let Some(path) = &path {
+                    self.cache_attr(path, Some(attr.clone()));
+                }
+                Ok(ReplyAttr {
+                    ttl: self.options.attr_ttl,
+                    attr: self.file_attr(inode, attr),
+                })
+            }
+            Err(e) => {
+                warn!("failed to get attributes of inode {}: {}", inode, e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        debug!("setattr: inode {}, {:?}", inode, set_attr);
+        let path = self.get_path(inode);
+        let fh = self.target_handle(inode, &path, fh)?;
+        let rpc_timestamp = |time: Option<fuse3::Timestamp>| {
+            time.map(|time| rpc_fs::Timestamp {
+                sec: time.sec,
+                nsec: time.nsec,
+            })
+        };
+
+        let caller = self.caller(&req);
+        let request = SetAttrRequest {
+            path: path.clone().unwrap_or_default(),
+            mode: set_attr.mode,
+            uid: set_attr
+                .uid
+                .map(|uid| self.options.id_map.uid_to_server(uid)),
+            gid: set_attr
+                .gid
+                .map(|gid| self.options.id_map.gid_to_server(gid)),
+            size: set_attr.size,
+            atime: rpc_timestamp(set_attr.atime),
+            mtime: rpc_timestamp(set_attr.mtime),
+            fh,
+        };
+
+        let response = self
+            .connection
+            .call(
+                Op::Mutating,
+                Some(&caller),
+                request,
+                |mut client, request| async move { client.set_attr(request).await },
+            )
+            .await;
+        if set_attr.size.is_some() {
+            self.invalidate_blocks(inode).await;
+        }
+        match response {
+            Ok(response) => {
+                let attr = response.into_inner().attributes.unwrap();
+                if let Some(path) = &path {
+                    self.cache_attr(path, Some(attr.clone()));
+                }
+                Ok(ReplyAttr {
+                    ttl: self.options.attr_ttl,
+                    attr: self.file_attr(inode, attr),
+                })
+            }
+            Err(e) => {
+                warn!("failed to set attributes of inode {}: {}", inode, e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn readlink(&self, req: Request, inode: u64) -> Result<ReplyData> {
+        debug!("readlink: inode {}", inode);
+        if let Some(path) = self.get_path(inode) {
+            let caller = self.caller(&req);
+            let request = ReadlinkRequest { path: path.clone() };
+
+            match self
+                .connection
+                .call(
+                    Op::Idempotent,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.readlink(request).await },
+                )
+                .await
+            {
                 Ok(response) => {
-                    let Attr {
-                        kind,
-                        permission,
-                        nlink,
-                        uid,
-                        gid,
-                        size,
-                        blksize,
-                        blocks,
-                        rdev,
-                        ..
-                    } = response.into_inner().attributes.unwrap();
-
-                    return Ok(ReplyAttr {
-                        ttl: Duration::from_secs(1),
-                        attr: FileAttr {
-                            ino: inode,
-                            generation: 0,
-                            size,
-                            blocks,
-                            atime: SystemTime::UNIX_EPOCH.into(),
-                            mtime: SystemTime::UNIX_EPOCH.into(),
-                            ctime: SystemTime::UNIX_EPOCH.into(),
-                            kind: if kind == rpc_fs::FileType::Directory.into() {
-                                fuse3::FileType::Directory
-                            } else {
-                                fuse3::FileType::RegularFile
-                            },
-                            perm: permission as u16,
-                            nlink,
-                            uid,
-                            gid,
-                            rdev,
-                            blksize,
-                        },
+                    return Ok(ReplyData {
+                        data: response.into_inner().target.into(),
                     });
                 }
                 Err(e) => {
-                    warn!("failed to get attributes of {}: {}", path, e);
+                    warn!("failed to read link {}: {}", path, e);
+                    return Err(errno(&e));
                 }
             }
         }
         Err(libc::ENOENT.into())
     }
 
-    async fn lookup(
+    async fn symlink(
         &self,
-        _req: Request,
+        req: Request,
         parent: u64,
-        name: &std::ffi::OsStr,
+        name: &OsStr,
+        link: &OsStr,
     ) -> Result<ReplyEntry> {
         debug!(
-            "lookup: parent {}, name {}",
-            parent,
-            name.to_str().unwrap().to_string()
+            "symlink: parent {}, name {:?}, link {:?}",
+            parent, name, link
         );
-        if let Some(parent_path) = self.get_path(parent).await {
-            let path = Path::new(&parent_path).join(name);
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(GetAttrRequest {
-                path: path.to_str().unwrap().to_string(),
-            });
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = SymlinkRequest {
+                path: path.clone(),
+                target: link.as_bytes().to_vec(),
+            };
 
-            let response = client.get_attr(request).await;
-            match response {
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.symlink(request).await },
+                )
+                .await
+            {
                 Ok(response) => {
-                    let Attr {
-                        inode,
-                        kind,
-                        permission,
-                        nlink,
-                        uid,
-                        gid,
-                        size,
-                        blksize,
-                        blocks,
-                        rdev,
-                    } = response.into_inner().attributes.unwrap();
-
+                    let attr = response.into_inner().attributes.unwrap();
+                    self.invalidate_attr(&parent_path);
+                    self.cache_attr(&path, Some(attr.clone()));
+                    let inode = self.lookup_inode(&attr, path, parent);
                     return Ok(ReplyEntry {
-                        ttl: Duration::from_secs(1),
-                        attr: FileAttr {
-                            ino: inode,
-                            generation: 0,
-                            size,
-                            blocks,
-                            atime: SystemTime::UNIX_EPOCH.into(),
-                            mtime: SystemTime::UNIX_EPOCH.into(),
-                            ctime: SystemTime::UNIX_EPOCH.into(),
-                            kind: if kind == rpc_fs::FileType::Directory.into() {
-                                fuse3::FileType::Directory
-                            } else {
-                                fuse3::FileType::RegularFile
-                            },
-                            perm: permission as u16,
-                            nlink,
-                            uid,
-                            gid,
-                            rdev,
-                            blksize,
-                        },
+                        ttl: self.options.entry_ttl,
+                        attr: self.file_attr(inode, attr),
                         generation: 0,
                     });
                 }
-                Err(_e) => {
-                    // TODO: check if this is just 404, or other errors
-                    info!("lookup: not found for path: {}", path.display());
+                Err(e) => {
+                    warn!("failed to create symlink {}: {}", path, e);
+                    return Err(errno(&e));
                 }
             }
         }
         Err(libc::ENOENT.into())
     }
 
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        debug!(
+            "lookup: parent {}, name {}",
+            parent,
+            name.to_str().unwrap().to_string()
+        );
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let attr = match self.cached_attr(&path, self.options.entry_ttl) {
+                Some(attr) => attr,
+                None => {
+                    let caller = self.caller(&req);
+                    let request = GetAttrRequest {
+                        path: path.clone(),
+                        fh: None,
+                    };
+
+                    let attr = match self
+                        .connection
+                        .call(
+                            Op::Idempotent,
+                            Some(&caller),
+                            request,
+                            |mut client, request| async move { client.get_attr(request).await },
+                        )
+                        .await
+                    {
+                        Ok(response) => response.into_inner().attributes,
+                        Err(e) if errno(&e).is_not_exist() => None,
+                        Err(e) => {
+                            debug!("lookup: failed for path {}: {}", path, e);
+                            return Err(errno(&e));
+                        }
+                    };
+                    self.cache_attr(&path, attr.clone());
+                    attr
+                }
+            };
+
+            return match attr {
+                Some(attr) => {
+                    let inode = self.lookup_inode(&attr, path, parent);
+                    Ok(ReplyEntry {
+                        ttl: self.options.entry_ttl,
+                        attr: self.file_attr(inode, attr),
+                        generation: 0,
+                    })
+                }
+                // a zero node id lets the kernel cache the missing name
+                None if !self.options.negative_ttl.is_zero() => Ok(ReplyEntry {
+                    ttl: self.options.negative_ttl,
+                    attr: self.file_attr(0, Attr::default()),
+                    generation: 0,
+                }),
+                None => Err(libc::ENOENT.into()),
+            };
+        }
+        Err(libc::ENOENT.into())
+    }
+
     async fn readdir(
         &self,
-        _req: Request,
+        req: Request,
         inode: u64,
         _fh: u64,
         offset: i64,
     ) -> Result<ReplyDirectory<Self::DirEntryStream>> {
         debug!("readdir: inode {}, offset {}", inode, offset);
-        if let Some(path) = self.get_path(inode).await {
-            // let path = path.clone();
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(ReadDirRequest {
+        if let Some(path) = self.get_path(inode) {
+            // offsets 1 and 2 are "." and "..", the server's offsets follow shifted by two
+            let caller = self.caller(&req);
+            let request = ReadDirRequest {
                 path: path.clone(),
-                offset,
-            });
+                offset: (offset - 2).max(0),
+            };
 
-            let response = client.read_dir(request).await;
+            let response = self
+                .connection
+                .call(
+                    Op::Idempotent,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.read_dir(request).await },
+                )
+                .await;
             match response {
                 Ok(response) => {
-                    let entries: Vec<_> = response
-                        .into_inner()
-                        .entries
-                        .into_iter()
-                        .map(move |entry| {
-                            let DEntry {
-                                kind,
-                                inode,
-                                offset,
-                                file_name: name,
-                            } = entry;
+                    let mut inode_table = self.inode_table.write().unwrap();
+                    let dotdot = inode_table.parent(inode).unwrap_or(1);
+                    let dots = [(".", inode, 1), ("..", dotdot, 2)].map(|(name, inode, offset)| {
+                        Ok(DirectoryEntry {
+                            inode,
+                            offset,
+                            kind: fuse3::FileType::Directory,
+                            name: name.into(),
+                        })
+                    });
 
-                            let inode = if name == "." || name == ".." {
-                                1
-                            } else {
-                                inode
-                            };
+                    let entries = response.into_inner().entries.into_iter().map(move |entry| {
+                        let DEntry {
+                            kind,
+                            inode,
+                            offset,
+                            file_name: name,
+                            dev,
+                        } = entry;
 
-                            Ok(DirectoryEntry {
-                                inode,
-                                offset: offset as i64,
-                                kind: {
-                                    if kind == rpc_fs::FileType::Directory.into() {
-                                        fuse3::FileType::Directory
-                                    } else {
-                                        fuse3::FileType::RegularFile
-                                    }
-                                },
-                                name: name.into(),
-                            })
+                        Ok(DirectoryEntry {
+                            inode: inode_table.number(dev, inode),
+                            offset: offset as i64 + 2,
+                            kind: file_type(kind),
+                            name: name.into(),
                         })
-                        .collect();
+                    });
 
-                        for entry in entries.iter() {
-                            let entry = entry.clone().unwrap();
-                            self.append_inode(
-                                entry.inode,
-                                Path::new(&path).join(&(entry.name)).to_str().unwrap().to_string(),
-                            ).await;
-                        };
+                    // the kernel looks the entries up before using them
+                    let chain: Vec<_> = dots
+                        .into_iter()
+                        .skip(offset as usize)
+                        .chain(entries)
+                        .collect();
 
                     Ok(ReplyDirectory {
-                        entries: stream::iter(entries.into_iter().skip(offset as usize)),
+                        entries: stream::iter(chain),
                     })
                 }
                 Err(e) => {
                     warn!("failed to read directory {}: {}", path, e);
-                    Err(libc::ENOENT.into())
+                    Err(errno(&e))
                 }
             }
         } else {
@@ -277,74 +1223,79 @@ impl Filesystem for GrpcFsClient {
 
     async fn readdirplus(
         &self,
-        _req: Request,
+        req: Request,
         parent: u64,
         _fh: u64,
         offset: u64,
         _lock_owner: u64,
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream>> {
         debug!("readdirplus: parent {}, offset {}", parent, offset);
-        if let Some(path) = self.get_path(parent).await {
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(ReadDirRequest {
+        if let Some(path) = self.get_path(parent) {
+            let dotdot = self.inode_table.read().unwrap().parent(parent).unwrap_or(1);
+            let mut dots = Vec::new();
+            for (name, inode, offset) in [(".", parent, 1), ("..", dotdot, 2)]
+                .into_iter()
+                .skip(offset as usize)
+            {
+                let attr = self.getattr(req, inode, None, 0).await?.attr;
+                dots.push(DirectoryEntryPlus {
+                    inode,
+                    generation: 0,
+                    kind: fuse3::FileType::Directory,
+                    name: name.into(),
+                    offset,
+                    attr,
+                    attr_ttl: self.options.attr_ttl,
+                    entry_ttl: self.options.entry_ttl,
+                });
+            }
+
+            // offsets 1 and 2 are "." and "..", the server's offsets follow shifted by two
+            let caller = self.caller(&req);
+            let request = ReadDirRequest {
                 path: path.clone(),
-                offset: offset.try_into().unwrap(), // blame if someone put minus-value into offset
-            });
+                offset: offset.saturating_sub(2) as i64,
+            };
 
-            let response = client.read_dir_plus(request).await;
+            let response = self
+                .connection
+                .call(
+                    Op::Idempotent,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.read_dir_plus(request).await },
+                )
+                .await;
             match response {
                 Ok(response) => {
-                    // TODO: add preceding entries('.' and '..')
+                    let mut inode_table = self.inode_table.write().unwrap();
+                    let mut attr_cache = self.attr_cache.lock().unwrap();
                     let entries: Vec<_> = response
                         .into_inner()
                         .entries
                         .into_iter()
-                        .map(move |entry| {
+                        .map(|entry| {
                             let DEntryPlus {
                                 kind,
                                 inode,
                                 offset,
                                 name,
                                 attr,
+                                dev,
                             } = entry;
 
-                            Ok(DirectoryEntryPlus {
-                                inode,
-                                offset: offset as i64 + 2,
-                                kind: {
-                                    if kind == rpc_fs::FileType::Directory.into() {
-                                        fuse3::FileType::Directory
-                                    } else {
-                                        fuse3::FileType::RegularFile
-                                    }
-                                },
-                                name: name.into(),
-                                generation: 0,
-                                entry_ttl: Duration::from_secs(1),
-                                attr_ttl: Duration::from_secs(1),
-                                attr: match attr {
-                                    Some(attr) => FileAttr {
-                                        ino: inode,
-                                        generation: 0,
-                                        size: attr.size,
-                                        blocks: attr.blocks,
-                                        atime: SystemTime::UNIX_EPOCH.into(),
-                                        mtime: SystemTime::UNIX_EPOCH.into(),
-                                        ctime: SystemTime::UNIX_EPOCH.into(),
-                                        kind: if kind == rpc_fs::FileType::Directory.into() {
-                                            fuse3::FileType::Directory
-                                        } else {
-                                            fuse3::FileType::RegularFile
-                                        },
-                                        perm: attr.permission as u16,
-                                        nlink: attr.nlink,
-                                        uid: attr.uid,
-                                        gid: attr.gid,
-                                        rdev: attr.rdev,
-                                        blksize: attr.blksize,
-                                    },
-                                    _ => FileAttr {
-                                        ino: inode,
+                            let (inode, attr, held) = match attr {
+                                Some(attr) => {
+                                    let entry_path = join_path(&path, OsStr::new(&name));
+                                    attr_cache.insert(entry_path.clone(), Some(attr.clone()));
+                                    let inode = inode_table.hold(&attr, entry_path, parent);
+                                    (inode, self.file_attr(inode, attr), true)
+                                }
+                                // a zero node id tells the kernel there is nothing to look up
+                                None => (
+                                    inode_table.number(dev, inode),
+                                    FileAttr {
+                                        ino: 0,
                                         generation: 0,
                                         size: 0,
                                         blocks: 0,
@@ -359,83 +1310,44 @@ impl Filesystem for GrpcFsClient {
                                         rdev: 0,
                                         blksize: 0,
                                     },
-                                },
-                            })
+                                    false,
+                                ),
+                            };
+
+                            let entry = DirectoryEntryPlus {
+                                inode,
+                                offset: offset as i64 + 2,
+                                kind: file_type(kind),
+                                name: name.into(),
+                                generation: 0,
+                                entry_ttl: self.options.entry_ttl,
+                                attr_ttl: self.options.attr_ttl,
+                                attr,
+                            };
+                            (entry, held)
                         })
                         .collect();
+                    drop(attr_cache);
+                    drop(inode_table);
 
+                    // the kernel does not count lookups for "." and ".."
+                    let chain: Vec<_> = dots
+                        .into_iter()
+                        .map(|entry| (entry, false))
+                        .chain(entries)
+                        .collect();
 
-                    for entry in entries.iter() {
-                        let entry = entry.clone().unwrap();
-                        self.append_inode(
-                            entry.inode,
-                            Path::new(&path).join(&(entry.name)).to_str().unwrap().to_string(),
-                        ).await;
-                    };
-                        
-
-                    let pre_chain: Vec<Result<DirectoryEntryPlus>> = vec![
-                        Ok(DirectoryEntryPlus {
-                            inode: parent,
-                            generation: 0,
-                            kind: fuse3::FileType::Directory,
-                            name: std::ffi::OsString::from("."),
-                            offset: 1,
-                            attr: FileAttr {
-                                ino: parent,
-                                generation: 0,
-                                size: 0,
-                                blocks: 0,
-                                atime: SystemTime::UNIX_EPOCH.into(),
-                                mtime: SystemTime::UNIX_EPOCH.into(),
-                                ctime: SystemTime::UNIX_EPOCH.into(),
-                                kind: fuse3::FileType::Directory,
-                                perm: 0o777,
-                                nlink: 0,
-                                uid: 0,
-                                gid: 0,
-                                rdev: 0,
-                                blksize: 0,
-                            },
-                            attr_ttl: Duration::from_secs(1),
-                            entry_ttl: Duration::from_secs(1),
-                        }),
-                        Ok(DirectoryEntryPlus {
-                            inode: 1,
-                            generation: 0,
-                            kind: fuse3::FileType::Directory,
-                            name: std::ffi::OsString::from(".."),
-                            offset: 1,
-                            attr: FileAttr {
-                                ino: 1,
-                                generation: 0,
-                                size: 0,
-                                blocks: 0,
-                                atime: SystemTime::UNIX_EPOCH.into(),
-                                mtime: SystemTime::UNIX_EPOCH.into(),
-                                ctime: SystemTime::UNIX_EPOCH.into(),
-                                kind: fuse3::FileType::Directory,
-                                perm: 0o777,
-                                nlink: 0,
-                                uid: 0,
-                                gid: 0,
-                                rdev: 0,
-                                blksize: 0,
-                            },
-                            attr_ttl: Duration::from_secs(1),
-                            entry_ttl: Duration::from_secs(1),
-                        }),
-                    ];
-
-                    let chain:Vec<_> = pre_chain.into_iter().chain(entries.into_iter()).collect();
-                    
                     Ok(ReplyDirectoryPlus {
-                        entries: stream::iter(chain.into_iter().skip(offset as usize)),
+                        entries: stream::iter(CountedEntries {
+                            entries: chain.into_iter(),
+                            pending: None,
+                            inode_table: self.inode_table.clone(),
+                        }),
                     })
                 }
-                Err(_) => {
-                    warn!("error on readdirplus grpc request!");
-                    Err(libc::ENOENT.into())
+                Err(e) => {
+                    warn!("failed to read directory {}: {}", path, e);
+                    Err(errno(&e))
                 }
             }
         } else {
@@ -443,27 +1355,40 @@ impl Filesystem for GrpcFsClient {
         }
     }
 
-    async fn open(&self, _req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
+    async fn open(&self, req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
         debug!("open: inode {}", inode);
-        match self.get_path(inode).await {
+        match self.get_path(inode) {
             Some(path) => {
-                let mut client = self.client.clone();
-                let request = tonic::Request::new(OpenRequest {
+                let caller = self.caller(&req);
+                let request = OpenRequest {
                     path: path.clone(),
                     flags,
-                });
-                let response = client.open(request).await;
+                };
+                let response = self
+                    .connection
+                    .call(
+                        Op::Mutating,
+                        Some(&caller),
+                        request,
+                        |mut client, request| async move { client.open(request).await },
+                    )
+                    .await;
+                if flags as i32 & libc::O_TRUNC != 0 {
+                    self.invalidate_attr(&path);
+                }
                 match response {
+                    // the reply flags are FOPEN_* flags for the kernel, not the open flags
                     Ok(response) => {
-                        let fd = response.into_inner().fd;
-                        Ok(ReplyOpen {
-                            fh: fd as u64,
-                            flags,
-                        })
+                        let OpenReply { fh, attributes } = response.into_inner();
+                        if let Some(attr) = attributes {
+                            self.validate_blocks(inode, &attr).await;
+                        }
+                        self.inode_table.write().unwrap().open_handle(inode, fh);
+                        Ok(ReplyOpen { fh, flags: 0 })
                     }
                     Err(e) => {
                         warn!("failed to open {}: {}", path, e);
-                        Err(libc::ENOENT.into())
+                        Err(errno(&e))
                     }
                 }
             }
@@ -475,29 +1400,210 @@ impl Filesystem for GrpcFsClient {
         &self,
         _req: Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         size: u32,
     ) -> Result<ReplyData> {
         debug!("read: inode {}, offset {}, size {}", ino, offset, size);
-        if let Some(path) = self.get_path(ino).await {
-            let mut client = self.client.clone();
-            let request = tonic::Request::new(ReadRequest {
+        if self.options.cache_size > 0 || self.disk_cache.is_some() {
+            return self.read_blocks(ino, fh, offset, size).await;
+        }
+
+        let request = ReadRequest {
+            fh,
+            offset,
+            size: size.into(),
+        };
+        // the whole stream is one attempt, so a read cut off midway is started again
+        let read = self
+            .connection
+            .call(
+                Op::Idempotent,
+                None,
+                request,
+                |mut client, request| async move {
+                    let mut chunks = client.read_stream(request).await?.into_inner();
+                    let mut data = BytesMut::with_capacity(size as usize);
+                    while let Some(chunk) = chunks.message().await? {
+                        data.extend_from_slice(&chunk.data);
+                    }
+                    Ok(data.freeze())
+                },
+            )
+            .await;
+        match read {
+            Ok(data) => Ok(ReplyData { data }),
+            Err(e) => {
+                warn!("failed to read inode {}: {}", ino, e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn release(
+        &self,
+        _req: Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        debug!("release: inode {}, fh {}", ino, fh);
+        self.read_offsets.lock().unwrap().remove(&fh);
+        self.in_flight.lock().unwrap().forget_handle(fh);
+        self.inode_table.write().unwrap().release_handle(ino, fh);
+        let request = ReleaseRequest { fh };
+        match self
+            .connection
+            .call(
+                Op::Mutating,
+                None,
+                request,
+                |mut client, request| async move { client.release(request).await },
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!("failed to release inode {}: {}", ino, e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn statfs(&self, _req: Request, inode: u64) -> Result<ReplyStatFs> {
+        debug!("statfs: inode {}", inode);
+        let request = StatFsRequest {};
+        match self
+            .connection
+            .call(
+                Op::Idempotent,
+                None,
+                request,
+                |mut client, request| async move { client.stat_fs(request).await },
+            )
+            .await
+        {
+            Ok(response) => {
+                let StatFsReply {
+                    blocks,
+                    bfree,
+                    bavail,
+                    files,
+                    ffree,
+                    bsize,
+                    namelen,
+                    frsize,
+                } = response.into_inner();
+                Ok(ReplyStatFs {
+                    blocks,
+                    bfree,
+                    bavail,
+                    files,
+                    ffree,
+                    bsize,
+                    namelen,
+                    frsize,
+                })
+            }
+            Err(e) => {
+                warn!("failed to stat filesystem: {}", e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        _req: Request,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        _flags: u32,
+    ) -> Result<ReplyWrite> {
+        debug!(
+            "write: inode {}, offset {}, size {}",
+            ino,
+            offset,
+            data.len()
+        );
+        let request = WriteRequest {
+            fh,
+            offset,
+            data: data.to_vec(),
+        };
+        let response = self
+            .connection
+            .call(
+                Op::Mutating,
+                None,
+                request,
+                |mut client, request| async move { client.write(request).await },
+            )
+            .await;
+        if let Some(path) = self.get_path(ino) {
+            self.invalidate_attr(&path);
+        }
+        self.invalidate_blocks(ino).await;
+        match response {
+            Ok(response) => Ok(ReplyWrite {
+                written: response.into_inner().written,
+            }),
+            Err(e) => {
+                warn!("failed to write inode {}: {}", ino, e);
+                Err(errno(&e))
+            }
+        }
+    }
+
+    async fn create(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        debug!("create: parent {}, name {:?}", parent, name);
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = CreateRequest {
                 path: path.clone(),
-                offset,
-                size: size.into(),
-            });
-            let response = client.read(request).await;
-            match response {
+                mode,
+                flags,
+            };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.create(request).await },
+                )
+                .await
+            {
                 Ok(response) => {
-                    let data = response.into_inner().data;
-                    Ok(ReplyData {
-                        data: bytes::Bytes::copy_from_slice(&data),
+                    let CreateReply { attributes, fh } = response.into_inner();
+                    let attr = attributes.unwrap();
+                    self.invalidate_attr(&parent_path);
+                    self.cache_attr(&path, Some(attr.clone()));
+                    let inode = self.lookup_inode(&attr, path, parent);
+                    self.validate_blocks(inode, &attr).await;
+                    self.inode_table.write().unwrap().open_handle(inode, fh);
+                    Ok(ReplyCreated {
+                        ttl: self.options.entry_ttl,
+                        attr: self.file_attr(inode, attr),
+                        generation: 0,
+                        fh,
+                        flags: 0,
                     })
                 }
                 Err(e) => {
-                    warn!("failed to read {}: {}", path, e);
-                    Err(libc::ENOENT.into())
+                    warn!("failed to create {}: {}", path, e);
+                    Err(errno(&e))
                 }
             }
         } else {
@@ -505,8 +1611,327 @@ impl Filesystem for GrpcFsClient {
         }
     }
 
-    async fn statfs(&self, _req: Request, _inode: u64) -> Result<ReplyStatFs> {
-        warn!("statfs isn't implemented yet");
-        Err(libc::ENOSYS.into())
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        debug!("mknod: parent {}, name {:?}, mode {:o}", parent, name, mode);
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = MknodRequest {
+                path: path.clone(),
+                mode,
+                rdev,
+            };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.mknod(request).await },
+                )
+                .await
+            {
+                Ok(response) => {
+                    let attr = response.into_inner().attributes.unwrap();
+                    self.invalidate_attr(&parent_path);
+                    self.cache_attr(&path, Some(attr.clone()));
+                    let inode = self.lookup_inode(&attr, path, parent);
+                    Ok(ReplyEntry {
+                        ttl: self.options.entry_ttl,
+                        attr: self.file_attr(inode, attr),
+                        generation: 0,
+                    })
+                }
+                Err(e) => {
+                    warn!("failed to create node {}: {}", path, e);
+                    Err(errno(&e))
+                }
+            }
+        } else {
+            Err(libc::ENOENT.into())
+        }
+    }
+
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+    ) -> Result<ReplyEntry> {
+        debug!("mkdir: parent {}, name {:?}", parent, name);
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = MkdirRequest {
+                path: path.clone(),
+                mode,
+            };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.mkdir(request).await },
+                )
+                .await
+            {
+                Ok(response) => {
+                    let attr = response.into_inner().attributes.unwrap();
+                    self.invalidate_attr(&parent_path);
+                    self.cache_attr(&path, Some(attr.clone()));
+                    let inode = self.lookup_inode(&attr, path, parent);
+                    Ok(ReplyEntry {
+                        ttl: self.options.entry_ttl,
+                        attr: self.file_attr(inode, attr),
+                        generation: 0,
+                    })
+                }
+                Err(e) => {
+                    warn!("failed to create directory {}: {}", path, e);
+                    Err(errno(&e))
+                }
+            }
+        } else {
+            Err(libc::ENOENT.into())
+        }
+    }
+
+    async fn unlink(&self, req: Request, parent: u64, name: &OsStr) -> Result<()> {
+        debug!("unlink: parent {}, name {:?}", parent, name);
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = UnlinkRequest { path: path.clone() };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.unlink(request).await },
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.invalidate_attr(&parent_path);
+                    self.invalidate_attr(&path);
+                    self.remove_path(&path);
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("failed to unlink {}: {}", path, e);
+                    Err(errno(&e))
+                }
+            }
+        } else {
+            Err(libc::ENOENT.into())
+        }
+    }
+
+    async fn rmdir(&self, req: Request, parent: u64, name: &OsStr) -> Result<()> {
+        debug!("rmdir: parent {}, name {:?}", parent, name);
+        if let Some(parent_path) = self.get_path(parent) {
+            let path = join_path(&parent_path, name);
+            let caller = self.caller(&req);
+            let request = RmdirRequest { path: path.clone() };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.rmdir(request).await },
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.invalidate_attr(&parent_path);
+                    self.invalidate_attr(&path);
+                    self.remove_path(&path);
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("failed to remove directory {}: {}", path, e);
+                    Err(errno(&e))
+                }
+            }
+        } else {
+            Err(libc::ENOENT.into())
+        }
+    }
+
+    async fn rename(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        debug!(
+            "rename: parent {}, name {:?}, new parent {}, new name {:?}",
+            parent, name, new_parent, new_name
+        );
+        let parent_path = self.get_path(parent);
+        let new_parent_path = self.get_path(new_parent);
+        if let (Some(parent_path), Some(new_parent_path)) = (parent_path, new_parent_path) {
+            let from = join_path(&parent_path, name);
+            let to = join_path(&new_parent_path, new_name);
+            let caller = self.caller(&req);
+            let request = RenameRequest {
+                from: from.clone(),
+                to: to.clone(),
+            };
+            match self
+                .connection
+                .call(
+                    Op::Mutating,
+                    Some(&caller),
+                    request,
+                    |mut client, request| async move { client.rename(request).await },
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.invalidate_attr(&parent_path);
+                    self.invalidate_attr(&new_parent_path);
+                    self.invalidate_attr_tree(&from);
+                    self.invalidate_attr_tree(&to);
+                    self.rename_path(&from, &to, new_parent);
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("failed to rename {} to {}: {}", from, to, e);
+                    Err(errno(&e))
+                }
+            }
+        } else {
+            Err(libc::ENOENT.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(inode: u64) -> Attr {
+        Attr {
+            dev: 1,
+            inode,
+            ..Attr::default()
+        }
+    }
+
+    #[test]
+    fn removed_files_keep_their_inode_until_forgotten() {
+        let mut table = InodeTable::new();
+        let inode = table.lookup(&attr(10), String::from("/a"), 1);
+
+        table.remove_path("/a");
+        assert!(table.server_id(inode).is_some());
+        assert_eq!(table.path(inode), None);
+        // a new file reusing the server inode is a different file
+        let reused = table.lookup(&attr(10), String::from("/b"), 1);
+        assert_ne!(reused, inode);
+
+        table.forget(inode, 1);
+        assert_eq!(table.server_id(inode), None);
+        assert_eq!(table.path(reused).as_deref(), Some("/b"));
+    }
+
+    #[test]
+    fn batch_forgets_keep_inodes_looked_up_meanwhile() {
+        let mut table = InodeTable::new();
+        let inode = table.lookup(&attr(10), String::from("/a"), 1);
+        table.lookup(&attr(10), String::from("/a"), 1);
+
+        // the kernel may have dropped the inode before taking the second lookup
+        table.forget_all(inode, Instant::now());
+        assert_eq!(table.path(inode).as_deref(), Some("/a"));
+        table.forget(inode, 1);
+        assert_eq!(table.path(inode), None);
+
+        let inode = table.lookup(&attr(11), String::from("/b"), 1);
+        table.lookup(&attr(11), String::from("/b"), 1);
+        table.forget_all(inode, Instant::now() + FORGET_RACE);
+        assert_eq!(table.path(inode), None);
+    }
+
+    #[test]
+    fn hard_links_share_an_inode_until_the_last_name_goes() {
+        let mut table = InodeTable::new();
+        let a = table.lookup(&attr(10), String::from("/a"), 1);
+        let b = table.lookup(&attr(10), String::from("/b"), 1);
+        assert_eq!(a, b);
+
+        table.remove_path("/b");
+        assert_eq!(table.path(a).as_deref(), Some("/a"));
+        table.rename_path("/a", "/c", 1);
+        assert_eq!(table.path(a).as_deref(), Some("/c"));
+        table.remove_path("/c");
+        assert_eq!(table.path(a), None);
+    }
+
+    #[test]
+    fn renamed_directories_take_their_children_along() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(&attr(10), String::from("/d"), 1);
+        let sub = table.lookup(&attr(11), String::from("/d/s"), dir);
+        let file = table.lookup(&attr(12), String::from("/d/s/f"), sub);
+        let beside = table.lookup(&attr(13), String::from("/dd"), 1);
+
+        table.rename_path("/d", "/e", 1);
+        assert_eq!(table.path(dir).as_deref(), Some("/e"));
+        assert_eq!(table.path(sub).as_deref(), Some("/e/s"));
+        assert_eq!(table.path(file).as_deref(), Some("/e/s/f"));
+        assert_eq!(table.parent(file), Some(sub));
+        // only whole components of the old path are moved
+        assert_eq!(table.path(beside).as_deref(), Some("/dd"));
+
+        // the kernel looks the child up again through the new path
+        let found = table.lookup(&attr(12), String::from("/e/s/f"), sub);
+        assert_eq!(found, file);
+        assert_eq!(table.entries[&file].paths, ["/e/s/f"]);
+    }
+
+    #[test]
+    fn listed_files_keep_their_number_when_looked_up() {
+        let mut table = InodeTable::new();
+        let listed = table.number(1, 10);
+        // the same inode number on another server filesystem is another file
+        assert_ne!(table.number(2, 10), listed);
+
+        let inode = table.lookup(&attr(10), String::from("/a"), 1);
+        assert_eq!(inode, listed);
+        table.forget(inode, 1);
+        assert_eq!(table.number(1, 10), listed);
+    }
+
+    #[tokio::test]
+    async fn released_handles_stop_their_reads() {
+        let pending = || future::pending::<Result<Bytes>>().boxed().shared();
+        let mut in_flight = InFlight::default();
+        in_flight.fetches.insert((10, 0), (1, 5, pending()));
+        in_flight.fetches.insert((10, 1), (2, 6, pending()));
+        let readahead = tokio::spawn(pending());
+        in_flight
+            .readahead
+            .insert(5, vec![readahead.abort_handle()]);
+
+        in_flight.forget_handle(5);
+        assert!(!in_flight.fetches.contains_key(&(10, 0)));
+        assert!(in_flight.fetches.contains_key(&(10, 1)));
+        assert!(readahead.await.unwrap_err().is_cancelled());
     }
 }
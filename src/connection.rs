@@ -0,0 +1,380 @@
+use crate::auth::AUTHORIZATION_METADATA_KEY;
+use crate::caller::Caller;
+use crate::client::rpc_fs::rpc_fs_client::RpcFsClient;
+use crate::client::rpc_fs::GetAttrRequest;
+use crate::server::{ERRNO_METADATA_KEY, EXPORT_METADATA_KEY};
+use crate::tls::ClientTls;
+use log::*;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+// first wait after a failed attempt, doubled up to MAX_BACKOFF
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// what the client does while the server cannot be reached, like nfs hard and soft mounts
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // keep waiting for the server instead of failing with EIO
+    pub hard: bool,
+    // deadline of a single attempt on a soft mount, and how long a hard mount gives a
+    // connection to show it is alive
+    pub timeout: Duration,
+    // attempts after the first one before a soft mount gives up
+    pub retries: u32,
+}
+
+// whether an rpc may be sent again when no reply came back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // sending it twice is harmless
+    Idempotent,
+    // it may have been applied already, so it is only sent again when the server
+    // could not be connected to, and so never got it; one lost after it was sent fails
+    // with EIO even on a hard mount
+    Mutating,
+}
+
+// the channel to the server, which reconnects by itself when it is used again
+#[derive(Debug, Clone)]
+pub struct Connection {
+    client: RpcFsClient<Channel>,
+    address: Arc<str>,
+    policy: RetryPolicy,
+    // sent with every request when the server asks for a bearer token
+    authorization: Option<AsciiMetadataValue>,
+    // sent with every request unless the server's default export is used
+    export: Option<AsciiMetadataValue>,
+    // set while calls fail because the server is unreachable
+    down: Arc<AtomicBool>,
+}
+
+impl Connection {
+    // nothing is connected until the first call
+    pub fn new(
+        address: &str,
+        policy: RetryPolicy,
+        tls: Option<&ClientTls>,
+        token: Option<&str>,
+        export: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // connecting gets part of the deadline, so that an unreachable server fails
+        // with a connection error rather than the deadline, and a hard mount, which
+        // waits for replies however long they take, finds a dead server by keepalive
+        let mut endpoint = Endpoint::from_shared(address.to_string())?
+            .connect_timeout(policy.timeout / 2)
+            .http2_keep_alive_interval(policy.timeout)
+            .keep_alive_timeout(policy.timeout)
+            .keep_alive_while_idle(true);
+        if let Some(tls) = tls {
+            endpoint = endpoint.tls_config(tls.config()?)?;
+        }
+        let channel = endpoint.connect_lazy();
+        let authorization = match token {
+            Some(token) => Some(
+                format!("Bearer {}", token)
+                    .parse()
+                    .map_err(|_| "bearer token must be printable ascii")?,
+            ),
+            None => None,
+        };
+        let export = match export {
+            Some(export) => Some(
+                export
+                    .parse()
+                    .map_err(|_| "export name must be printable ascii")?,
+            ),
+            None => None,
+        };
+        Ok(Connection {
+            client: RpcFsClient::new(channel),
+            address: address.into(),
+            policy,
+            authorization,
+            export,
+            down: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // runs `rpc` with a handle to the channel and a copy of `request` with our metadata
+    // and the caller's, retrying with backoff while the server cannot be reached as far
+    // as the policy and `op` allow
+    pub async fn call<R, T, F, Fut>(
+        &self,
+        op: Op,
+        caller: Option<&Caller>,
+        request: R,
+        rpc: F,
+    ) -> Result<T, Status>
+    where
+        R: Clone,
+        F: FnMut(RpcFsClient<Channel>, tonic::Request<R>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        // a mutating rpc is only repeated when it never got through, so a hard mount waits for
+        // a server it knows is down rather than trying it
+        if op == Op::Mutating && self.policy.hard && self.down.load(Ordering::Relaxed) {
+            self.wait_for_server().await;
+        }
+        self.retry(op, caller, request, rpc).await
+    }
+
+    async fn retry<R, T, F, Fut>(
+        &self,
+        op: Op,
+        caller: Option<&Caller>,
+        request: R,
+        mut rpc: F,
+    ) -> Result<T, Status>
+    where
+        R: Clone,
+        F: FnMut(RpcFsClient<Channel>, tonic::Request<R>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            // a hard mount waits for a request that was sent, however slow the server is,
+            // as sending it again would only start the same work over
+            let sent = rpc(self.client.clone(), self.request(request.clone(), caller));
+            let result = if self.policy.hard {
+                sent.await
+            } else {
+                self.within_deadline(sent).await
+            };
+            let status = match result {
+                Ok(reply) => {
+                    self.mark_up();
+                    return Ok(reply);
+                }
+                Err(status) if !unreachable(&status) => {
+                    self.mark_up();
+                    return Err(status);
+                }
+                Err(status) => status,
+            };
+            self.mark_down(&status);
+
+            attempt += 1;
+            let resend = op == Op::Idempotent || never_sent(&status);
+            if !resend || (!self.policy.hard && attempt > self.policy.retries) {
+                return Err(status);
+            }
+            debug!("retrying in {:?}: {}", backoff, status);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // a single attempt within the deadline whatever the policy, for what is asked before
+    // there is a mount to wait on
+    pub async fn call_once<R, T, F, Fut>(
+        &self,
+        caller: Option<&Caller>,
+        request: R,
+        rpc: F,
+    ) -> Result<T, Status>
+    where
+        F: FnOnce(RpcFsClient<Channel>, tonic::Request<R>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let sent = rpc(self.client.clone(), self.request(request, caller));
+        self.within_deadline(sent).await
+    }
+
+    async fn within_deadline<T>(
+        &self,
+        sent: impl Future<Output = Result<T, Status>>,
+    ) -> Result<T, Status> {
+        match tokio::time::timeout(self.policy.timeout, sent).await {
+            Ok(result) => result,
+            Err(_) => Err(Status::deadline_exceeded(format!(
+                "no reply within {:?}",
+                self.policy.timeout
+            ))),
+        }
+    }
+
+    fn request<R>(&self, message: R, caller: Option<&Caller>) -> tonic::Request<R> {
+        let mut request = tonic::Request::new(message);
+        if let Some(caller) = caller {
+            caller.insert_into(request.metadata_mut());
+        }
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_METADATA_KEY, authorization.clone());
+        }
+        if let Some(export) = &self.export {
+            request
+                .metadata_mut()
+                .insert(EXPORT_METADATA_KEY, export.clone());
+        }
+        request
+    }
+
+    async fn wait_for_server(&self) {
+        let request = GetAttrRequest {
+            path: String::from("/"),
+            fh: None,
+        };
+        let _ = self
+            .retry(
+                Op::Idempotent,
+                None,
+                request,
+                |mut client, request| async move { client.get_attr(request).await },
+            )
+            .await;
+    }
+
+    fn mark_down(&self, status: &Status) {
+        if !self.down.swap(true, Ordering::Relaxed) {
+            let action = if self.policy.hard {
+                "still trying"
+            } else {
+                "timing out"
+            };
+            warn!(
+                "server {} not responding, {}: {}",
+                self.address,
+                action,
+                status.message()
+            );
+        }
+    }
+
+    fn mark_up(&self) {
+        if self.down.swap(false, Ordering::Relaxed) {
+            info!("server {} OK", self.address);
+        }
+    }
+}
+
+// the request failed while connecting, before any of it was sent
+fn never_sent(status: &Status) -> bool {
+    let mut source = std::error::Error::source(status);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<hyper::Error>() {
+            return error.is_connect();
+        }
+        source = error.source();
+    }
+    false
+}
+
+// the reply did not come from our server, as opposed to an error it returned
+fn unreachable(status: &Status) -> bool {
+    let from_server = status.metadata().contains_key(ERRNO_METADATA_KEY);
+    !from_server
+        && matches!(
+            status.code(),
+            Code::Unavailable | Code::Unknown | Code::DeadlineExceeded
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::errno_status;
+    use std::sync::atomic::AtomicU32;
+
+    fn connection(address: &str, hard: bool) -> Connection {
+        let policy = RetryPolicy {
+            hard,
+            timeout: Duration::from_millis(200),
+            retries: 2,
+        };
+        Connection::new(address, policy, None, None, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refused_connections_were_never_sent() {
+        // a port nothing listens on once the listener is gone
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let request = GetAttrRequest {
+            path: String::from("/"),
+            fh: None,
+        };
+        let status = connection(&address, false)
+            .call_once(None, request, |mut client, request| async move {
+                client.get_attr(request).await
+            })
+            .await
+            .unwrap_err();
+        assert!(unreachable(&status), "{:?}", status);
+        assert!(never_sent(&status), "{:?}", status);
+    }
+
+    #[test]
+    fn errors_from_the_server_are_not_retried() {
+        let returned = errno_status(Code::Unavailable, "busy", libc::EAGAIN);
+        assert!(!unreachable(&returned));
+        assert!(!never_sent(&returned));
+        assert!(!unreachable(&Status::not_found("gone")));
+
+        // lost on the way without a connection error, so it may have arrived
+        let lost = Status::unavailable("connection reset");
+        assert!(unreachable(&lost));
+        assert!(!never_sent(&lost));
+    }
+
+    // calls `reply` through `connection` as `op`, counting the attempts
+    async fn attempts<T>(
+        connection: &Connection,
+        op: Op,
+        reply: impl Fn() -> Result<T, Status>,
+        delay: Duration,
+    ) -> (Result<T, Status>, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = connection
+            .call(op, None, (), |_, _| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                let reply = reply();
+                async move {
+                    tokio::time::sleep(delay).await;
+                    reply
+                }
+            })
+            .await;
+        (result, attempts.into_inner())
+    }
+
+    #[tokio::test]
+    async fn hard_mounts_wait_for_slow_replies() {
+        let slow = Duration::from_millis(600);
+        let hard = connection("http://127.0.0.1:1", true);
+        let soft = connection("http://127.0.0.1:1", false);
+
+        let (result, count) = attempts(&hard, Op::Idempotent, || Ok(()), slow).await;
+        assert!(result.is_ok());
+        assert_eq!(count, 1);
+
+        let (result, count) = attempts(&soft, Op::Idempotent, || Ok(()), slow).await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    async fn only_requests_safe_to_repeat_are_resent_once_sent() {
+        let hard = connection("http://127.0.0.1:1", true);
+        let lost = || Err::<(), _>(Status::unavailable("connection reset"));
+
+        let (result, count) = attempts(&hard, Op::Mutating, lost, Duration::ZERO).await;
+        assert_eq!(result.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(count, 1);
+
+        let soft = connection("http://127.0.0.1:1", false);
+        let (result, count) = attempts(&soft, Op::Idempotent, lost, Duration::ZERO).await;
+        assert!(result.is_err());
+        assert_eq!(count, 3);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::block_cache::Validator;
+use bytes::Bytes;
+use log::*;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// name of the file in each file directory holding "<mtime sec> <mtime nsec> <size>"
+// of the version its blocks were read from
+const VALIDATOR_FILE: &str = "validator";
+
+// blocks of file data kept on disk across mounts, in one directory per server file:
+//
+//   <dir>/<server>/<dev>-<ino>/validator
+//   <dir>/<server>/<dev>-<ino>/<block index>
+//
+// the least recently used blocks are removed once more than `capacity` bytes are cached
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    // size and last use of every cached block, the blocks of a file sort together
+    blocks: BTreeMap<PathBuf, (u64, u64)>,
+    lru: BTreeMap<u64, PathBuf>,
+    used: u64,
+    capacity: u64,
+    tick: u64,
+}
+
+impl DiskCache {
+    // picks up the blocks cached by earlier mounts of the same server
+    pub fn open(dir: &Path, server: &str, capacity: u64) -> io::Result<Self> {
+        let server: String = server
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let dir = dir.join(server);
+        fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for file_dir in fs::read_dir(&dir)? {
+            let file_dir = file_dir?.path();
+            if !file_dir.is_dir() {
+                continue;
+            }
+            let blocks = found.len();
+            for block in fs::read_dir(&file_dir)? {
+                let block = block?;
+                if block.file_name() == VALIDATOR_FILE {
+                    continue;
+                }
+                // left behind by a crash while writing the block
+                if block.path().extension().is_some() {
+                    fs::remove_file(block.path())?;
+                    continue;
+                }
+                let metadata = block.metadata()?;
+                found.push((metadata.modified()?, block.path(), metadata.len()));
+            }
+            // the validator is useless once the last block of a file is gone
+            if found.len() == blocks {
+                fs::remove_dir_all(&file_dir)?;
+            }
+        }
+        // blocks are touched whenever they are used, so the oldest go first
+        found.sort();
+
+        let mut disk_cache = DiskCache {
+            dir,
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            used: 0,
+            capacity,
+            tick: 0,
+        };
+        for (_, path, size) in found {
+            disk_cache.track(path, size);
+        }
+        disk_cache.evict();
+        info!(
+            "disk cache {}: {} bytes in {} blocks",
+            disk_cache.dir.display(),
+            disk_cache.used,
+            disk_cache.blocks.len()
+        );
+        Ok(disk_cache)
+    }
+
+    fn file_dir(&self, server_id: (u64, u64)) -> PathBuf {
+        self.dir.join(format!("{}-{}", server_id.0, server_id.1))
+    }
+
+    pub fn get(&mut self, server_id: (u64, u64), block: u64) -> Option<Bytes> {
+        let path = self.file_dir(server_id).join(block.to_string());
+        let &(size, _) = self.blocks.get(&path)?;
+        match fs::read(&path) {
+            Ok(data) => {
+                self.track(path.clone(), size);
+                touch(&path);
+                Some(data.into())
+            }
+            Err(e) => {
+                warn!("failed to read cached block {}: {}", path.display(), e);
+                self.remove(&path);
+                None
+            }
+        }
+    }
+
+    // a block is only written next to the validator of the version it was read from, a
+    // file written to or reopened as another version since keeps none of it
+    pub fn insert(&mut self, server_id: (u64, u64), block: u64, validator: Validator, data: &[u8]) {
+        let file_dir = self.file_dir(server_id);
+        let recorded = fs::read_to_string(file_dir.join(VALIDATOR_FILE));
+        if data.len() as u64 > self.capacity
+            || !recorded.is_ok_and(|recorded| recorded == validator_contents(validator))
+        {
+            return;
+        }
+        let path = file_dir.join(block.to_string());
+        self.remove(&path);
+
+        // written aside first, so that a crash never leaves a partial block behind
+        let partial = path.with_extension("partial");
+        if let Err(e) = fs::write(&partial, data).and_then(|_| fs::rename(&partial, &path)) {
+            warn!("failed to cache block {}: {}", path.display(), e);
+            let _ = fs::remove_file(&partial);
+            return;
+        }
+        self.track(path, data.len() as u64);
+        self.evict();
+    }
+
+    // compares the validator file with the version being opened, rewriting it and
+    // deleting the block files when they differ, returns whether the blocks were kept
+    pub fn validate(&mut self, server_id: (u64, u64), validator: Validator) -> bool {
+        let file_dir = self.file_dir(server_id);
+        let path = file_dir.join(VALIDATOR_FILE);
+        let contents = validator_contents(validator);
+        if fs::read_to_string(&path).is_ok_and(|cached| cached == contents) {
+            return true;
+        }
+
+        self.invalidate(server_id);
+        if let Err(e) = fs::create_dir_all(&file_dir).and_then(|_| fs::write(&path, contents)) {
+            warn!("failed to write {}: {}", path.display(), e);
+        }
+        false
+    }
+
+    pub fn invalidate(&mut self, server_id: (u64, u64)) {
+        let file_dir = self.file_dir(server_id);
+        for path in self.file_blocks(&file_dir) {
+            self.remove(&path);
+        }
+        let _ = fs::remove_file(file_dir.join(VALIDATOR_FILE));
+    }
+
+    fn file_blocks(&self, file_dir: &Path) -> Vec<PathBuf> {
+        self.blocks
+            .range(file_dir.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(file_dir))
+            .cloned()
+            .collect()
+    }
+
+    fn track(&mut self, path: PathBuf, size: u64) {
+        self.tick += 1;
+        self.used += size;
+        self.lru.insert(self.tick, path.clone());
+        if let Some((old_size, old_use)) = self.blocks.insert(path, (size, self.tick)) {
+            self.used -= old_size;
+            self.lru.remove(&old_use);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            let Some((_, path)) = self.lru.pop_first() else {
+                break;
+            };
+            self.remove(&path);
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((size, last_use)) = self.blocks.remove(path) {
+            self.lru.remove(&last_use);
+            self.used -= size;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("failed to remove cached block {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn validator_contents(validator: Validator) -> String {
+    format!(
+        "{} {} {}\n",
+        validator.mtime.0, validator.mtime.1, validator.size
+    )
+}
+
+// records the use of a block in its mtime, which orders eviction after a remount
+fn touch(path: &Path) {
+    if let Ok(cpath) = CString::new(path.as_os_str().as_bytes()) {
+        unsafe { libc::utimes(cpath.as_ptr(), std::ptr::null()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const SERVER: &str = "http://[::1]:50051";
+    const FILE: (u64, u64) = (1, 2);
+
+    fn validator(sec: i64, size: u64) -> Validator {
+        Validator {
+            mtime: (sec, 0),
+            size,
+        }
+    }
+
+    #[test]
+    fn blocks_are_reused_after_reopening() {
+        let dir = TempDir::new("disk-cache-reopen");
+        let version = validator(1, 10);
+        let mut disk_cache = DiskCache::open(&dir, SERVER, 1 << 20).unwrap();
+        assert!(!disk_cache.validate(FILE, version));
+        disk_cache.insert(FILE, 0, version, b"block 0");
+        drop(disk_cache);
+
+        let mut disk_cache = DiskCache::open(&dir, SERVER, 1 << 20).unwrap();
+        assert_eq!(disk_cache.used, 7);
+        assert!(disk_cache.validate(FILE, version));
+        assert_eq!(disk_cache.get(FILE, 0).as_deref(), Some(&b"block 0"[..]));
+        // another server's blocks are kept apart
+        let mut other = DiskCache::open(&dir, "http://[::1]:50052", 1 << 20).unwrap();
+        assert_eq!(other.get(FILE, 0), None);
+    }
+
+    #[test]
+    fn blocks_of_another_version_are_dropped() {
+        let dir = TempDir::new("disk-cache-versions");
+        let mut disk_cache = DiskCache::open(&dir, SERVER, 1 << 20).unwrap();
+        let version = validator(1, 10);
+
+        for changed in [validator(2, 10), validator(1, 11)] {
+            disk_cache.validate(FILE, version);
+            disk_cache.insert(FILE, 0, version, b"block 0");
+            assert!(disk_cache.get(FILE, 0).is_some());
+            assert!(!disk_cache.validate(FILE, changed));
+            assert_eq!(disk_cache.get(FILE, 0), None);
+        }
+
+        // a read of the old version that ends after the file was reopened is not kept,
+        // nor one after the file was written to
+        disk_cache.insert(FILE, 0, version, b"block 0");
+        assert_eq!(disk_cache.get(FILE, 0), None);
+        disk_cache.validate(FILE, version);
+        disk_cache.invalidate(FILE);
+        disk_cache.insert(FILE, 0, version, b"block 0");
+        assert_eq!(disk_cache.get(FILE, 0), None);
+        assert_eq!(disk_cache.used, 0);
+    }
+
+    #[test]
+    fn least_recently_used_blocks_go_first() {
+        let dir = TempDir::new("disk-cache-lru");
+        let mut disk_cache = DiskCache::open(&dir, SERVER, 10).unwrap();
+        let version = validator(1, 12);
+        disk_cache.validate(FILE, version);
+
+        disk_cache.insert(FILE, 0, version, b"0000");
+        disk_cache.insert(FILE, 1, version, b"1111");
+        assert!(disk_cache.get(FILE, 0).is_some());
+        disk_cache.insert(FILE, 2, version, b"2222");
+
+        assert_eq!(disk_cache.used, 8);
+        assert!(disk_cache.get(FILE, 0).is_some());
+        assert_eq!(disk_cache.get(FILE, 1), None);
+        assert!(disk_cache.get(FILE, 2).is_some());
+        // a block larger than the whole cache is never written
+        disk_cache.insert(FILE, 3, version, &[0; 11]);
+        assert_eq!(disk_cache.get(FILE, 3), None);
+        assert_eq!(disk_cache.used, 8);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::caller::Caller;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// the server's nobody, who squashed users become unless told otherwise
+pub const DEFAULT_ANON_ID: u32 = 65534;
+
+// which client users are made anonymous on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Squash {
+    #[default]
+    None,
+    Root,
+    All,
+}
+
+// one direction of a mapping between client and server ids
+#[derive(Debug, Default)]
+struct Ids {
+    to_server: HashMap<u32, u32>,
+    to_client: HashMap<u32, u32>,
+}
+
+impl Ids {
+    fn insert(&mut self, client: u32, server: u32) -> Result<(), String> {
+        if self.to_server.insert(client, server).is_some() {
+            return Err(format!("{} is mapped twice", client));
+        }
+        if self.to_client.insert(server, client).is_some() {
+            return Err(format!("{} is mapped to twice", server));
+        }
+        Ok(())
+    }
+}
+
+// translates users and groups between the client host and the server host, read from
+// a file with lines like
+//
+//   uid <client uid> <server uid>
+//   gid <client gid> <server gid>
+//
+// ids without a line are the same on both hosts
+#[derive(Debug)]
+pub struct IdMap {
+    uids: Ids,
+    gids: Ids,
+    squash: Squash,
+    anon_uid: u32,
+    anon_gid: u32,
+}
+
+impl Default for IdMap {
+    fn default() -> Self {
+        IdMap {
+            uids: Ids::default(),
+            gids: Ids::default(),
+            squash: Squash::None,
+            anon_uid: DEFAULT_ANON_ID,
+            anon_gid: DEFAULT_ANON_ID,
+        }
+    }
+}
+
+impl IdMap {
+    pub fn new(squash: Squash, anon_uid: u32, anon_gid: u32) -> Self {
+        IdMap {
+            squash,
+            anon_uid,
+            anon_gid,
+            ..IdMap::default()
+        }
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        self.parse(&contents)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(&mut self, contents: &str) -> Result<(), String> {
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [kind, client, server] = fields[..] else {
+                return Err(format!(
+                    "line {}: expected uid|gid <client id> <server id>",
+                    number + 1
+                ));
+            };
+            let ids = match kind {
+                "uid" => &mut self.uids,
+                "gid" => &mut self.gids,
+                _ => return Err(format!("line {}: unknown kind '{}'", number + 1, kind)),
+            };
+            let parse = |id: &str| {
+                id.parse()
+                    .map_err(|e| format!("line {}: invalid id '{}': {}", number + 1, id, e))
+            };
+            ids.insert(parse(client)?, parse(server)?)
+                .map_err(|e| format!("line {}: {} {}", number + 1, kind, e))?;
+        }
+        Ok(())
+    }
+
+    fn squashed(&self, client_uid: u32) -> bool {
+        match self.squash {
+            Squash::None => false,
+            Squash::Root => client_uid == 0,
+            Squash::All => true,
+        }
+    }
+
+    // only callers are squashed, the ids they hand out (to chown, say) are just mapped
+    pub fn uid_to_server(&self, uid: u32) -> u32 {
+        self.uids.to_server.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub fn gid_to_server(&self, gid: u32) -> u32 {
+        self.gids.to_server.get(&gid).copied().unwrap_or(gid)
+    }
+
+    pub fn uid_to_client(&self, uid: u32) -> u32 {
+        self.uids.to_client.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub fn gid_to_client(&self, gid: u32) -> u32 {
+        self.gids.to_client.get(&gid).copied().unwrap_or(gid)
+    }
+
+    // a squashed caller keeps none of its supplementary groups, and root squash takes
+    // root's group from everyone else
+    pub fn caller_to_server(&self, caller: Caller) -> Caller {
+        if self.squashed(caller.uid) {
+            return Caller {
+                uid: self.anon_uid,
+                gid: self.anon_gid,
+                groups: Vec::new(),
+            };
+        }
+        let gid_to_server = |gid| match (self.squash, gid) {
+            (Squash::Root, 0) => self.anon_gid,
+            _ => self.gid_to_server(gid),
+        };
+        Caller {
+            uid: self.uid_to_server(caller.uid),
+            gid: gid_to_server(caller.gid),
+            groups: caller
+                .groups
+                .iter()
+                .map(|&group| gid_to_server(group))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_mapped_both_ways() {
+        let mut id_map = IdMap::default();
+        id_map.parse("uid 1000 5001\ngid 100 5000\n").unwrap();
+
+        assert_eq!(id_map.uid_to_server(1000), 5001);
+        assert_eq!(id_map.uid_to_client(5001), 1000);
+        assert_eq!(id_map.gid_to_server(100), 5000);
+        assert_eq!(id_map.gid_to_client(5000), 100);
+        // unmapped ids are the same on both hosts
+        assert_eq!(id_map.uid_to_server(0), 0);
+        assert_eq!(id_map.uid_to_client(1000), 1000);
+
+        assert!(IdMap::default().parse("uid 1 2\nuid 1 3").is_err());
+        assert!(IdMap::default().parse("uid 1 2\nuid 3 2").is_err());
+        assert!(IdMap::default().parse("user 1 2").is_err());
+    }
+
+    #[test]
+    fn squashed_callers_become_anonymous() {
+        let caller = |uid, gid| Caller {
+            uid,
+            gid,
+            groups: vec![0, 100],
+        };
+        let anonymous = Caller {
+            uid: 99,
+            gid: 98,
+            groups: vec![],
+        };
+
+        let root_squash = IdMap::new(Squash::Root, 99, 98);
+        assert_eq!(root_squash.caller_to_server(caller(0, 0)), anonymous);
+        assert_eq!(
+            root_squash.caller_to_server(caller(1000, 100)),
+            Caller {
+                uid: 1000,
+                gid: 100,
+                groups: vec![98, 100],
+            }
+        );
+
+        let all_squash = IdMap::new(Squash::All, 99, 98);
+        assert_eq!(all_squash.caller_to_server(caller(1000, 100)), anonymous);
+        // files can still be given to the users they name
+        assert_eq!(all_squash.uid_to_server(1000), 1000);
+        assert_eq!(root_squash.uid_to_server(0), 0);
+        assert_eq!(root_squash.gid_to_server(0), 0);
+    }
+}
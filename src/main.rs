@@ -1,20 +1,41 @@
+// tonic::Status is large, but it is what the gRPC handlers and their helpers return anyway
+#![allow(clippy::result_large_err)]
+
+pub mod args;
+pub mod auth;
+pub mod block_cache;
+pub mod caller;
 pub mod client;
+pub mod connection;
+pub mod disk_cache;
+pub mod id_map;
 pub mod server;
+#[cfg(test)]
+mod testing;
+pub mod tls;
 
-use client::GrpcFsClient;
+use args::{usage, ClientArgs, ServerArgs};
+use auth::Policy;
+use client::{ClientOptions, GrpcFsClient};
+use connection::RetryPolicy;
 use server::rpc_fs::rpc_fs_server::RpcFsServer;
-use server::GrpcFs;
+use server::{Export, GrpcFs, HANDLE_LEASE};
+use std::sync::Arc;
 use tonic::transport::Server;
 
 use fuse3::raw::prelude::*;
 use fuse3::MountOptions;
 
-fn usage(exe_name: &str) {
-    println!("usage: {exe_name} [subcommand] <options...>");
-    println!();
-    println!("subcommands:");
-    println!("    server");
-    println!("    client");
+fn parse_or_exit<T>(exe_name: &str, parsed: Result<T, String>) -> T {
+    match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {e}");
+            eprintln!();
+            usage(exe_name);
+            std::process::exit(2);
+        }
+    }
 }
 
 #[tokio::main]
@@ -25,22 +46,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(subcommand) = args.get(1) {
         match &**subcommand {
             "server" => {
-                // let addr = "0.0.0.0:50051".parse()?;
-                let addr = std::env::var("SERVER_ADDRESS").unwrap().parse()?;
-                let grpc_fs = GrpcFs::default();
+                let server_args = parse_or_exit(&args[0], ServerArgs::parse(&args[2..]));
+                let default_policy = match &server_args.policy {
+                    Some(policy) => Some(Arc::new(Policy::load(policy)?)),
+                    None => None,
+                };
+                let has_policy = server_args.policy.is_some()
+                    || server_args
+                        .exports
+                        .iter()
+                        .any(|export| export.policy.is_some());
+                if has_policy && server_args.tls.is_none() {
+                    log::warn!("bearer tokens are sent in the clear without --tls-cert");
+                }
+                let mut exports = Vec::new();
+                for export in &server_args.exports {
+                    let policy = match &export.policy {
+                        Some(policy) => Some(Arc::new(Policy::load(policy)?)),
+                        None => default_policy.clone(),
+                    };
+                    exports.push(Export::new(
+                        &export.name,
+                        &export.root,
+                        export.read_only,
+                        policy,
+                    )?);
+                }
+                let grpc_fs = Arc::new(GrpcFs::with_exports(exports)?);
+                // closes the files of clients that went away without releasing them
+                let expiring = grpc_fs.clone();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(HANDLE_LEASE / 4);
+                    loop {
+                        let now = interval.tick().await.into_std();
+                        let expired = expiring.expire_handles(now);
+                        if expired > 0 {
+                            log::info!("closed {} abandoned file handles", expired);
+                        }
+                    }
+                });
 
-                Server::builder()
-                    .add_service(RpcFsServer::new(grpc_fs))
-                    .serve(addr)
+                let mut server = Server::builder();
+                if let Some(tls) = &server_args.tls {
+                    server = server.tls_config(tls.config()?)?;
+                }
+                server
+                    .add_service(RpcFsServer::from_arc(grpc_fs))
+                    .serve(server_args.listen)
                     .await?;
             }
             "client" => {
-                let addr = String::from("http://[::1]:50051");
-                let mountpoint = String::from("/tmp/mnt");
+                let client_args = parse_or_exit(&args[0], ClientArgs::parse(&args[2..]));
                 let mut options = MountOptions::default();
-                options.read_only(true).fs_name("GrpcFs"); //force_readdir_plus(true);
+                options
+                    .fs_name(client_args.fs_name)
+                    .read_only(client_args.read_only)
+                    .allow_other(client_args.allow_other); //force_readdir_plus(true);
+
+                let client_options = ClientOptions {
+                    uid: client_args.uid,
+                    gid: client_args.gid,
+                    attr_ttl: client_args.attr_ttl,
+                    entry_ttl: client_args.entry_ttl,
+                    negative_ttl: client_args.negative_ttl,
+                    cache_size: client_args.cache_size,
+                    readahead: client_args.readahead,
+                    disk_cache: client_args.disk_cache,
+                    disk_cache_size: client_args.disk_cache_size,
+                    retry: RetryPolicy {
+                        hard: client_args.hard,
+                        timeout: client_args.timeout,
+                        retries: client_args.retries,
+                    },
+                    tls: client_args.tls,
+                    token: client_args.token,
+                    id_map: client_args.id_map,
+                    export: client_args.export,
+                };
+                let client = GrpcFsClient::new(client_args.server, client_options)?;
+                if client_args.list_exports {
+                    for export in client.list_exports().await? {
+                        let name = match &*export.name {
+                            "" => "(default)",
+                            name => name,
+                        };
+                        let access = if export.read_only { "ro" } else { "rw" };
+                        println!("{name}\t{access}");
+                    }
+                    return Ok(());
+                }
+                // a mount every operation fails on is no use, anything else may pass later
+                if let Err(e) = client.check_export().await {
+                    match e.code() {
+                        tonic::Code::NotFound | tonic::Code::Unauthenticated => {
+                            eprintln!("error: {}", e.message());
+                            eprintln!(
+                                "the exports that can be mounted are shown by --list-exports"
+                            );
+                            std::process::exit(1);
+                        }
+                        // the server may just be down, which the retry policy deals with
+                        _ => log::warn!("export not checked: {}", e),
+                    }
+                }
                 Session::new(options)
-                    .mount_with_unprivileged(GrpcFsClient::new(addr).await, mountpoint)
+                    .mount_with_unprivileged(client, client_args.mountpoint)
                     .await?
                     .await?;
             }
@@ -1,8 +1,19 @@
+use crate::auth::{Access, Grants, Policy};
+use crate::caller::{Caller, MAY_EXEC, MAY_READ, MAY_WRITE};
+use futures_util::stream::{self, Stream};
 use log::*;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::hash::BuildHasher;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
 use std::os::unix::prelude::*;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use tonic::{Request, Response, Status};
 
@@ -17,8 +28,751 @@ pub mod rpc_fs {
 // as it requires too much work on handler side
 // instead, we do inode-to-path translation table in client-side,
 // and the path is sent over RPCs
-#[derive(Debug, Default)]
-pub struct GrpcFs {}
+//
+// paths sent by the client are absolute paths inside of the export root,
+// i.e. "/" on the client side is `root` on the server side
+//
+// a server can have several exports, each with its own root, and every request
+// names the one it is for, or none for the default export
+//
+// files opened by the client are kept open in `handles` until they are released,
+// so reads and writes are addressed by handle rather than by path; a handle that is
+// neither used nor renewed for HANDLE_LEASE is taken to be left behind by a client
+// that went away, and closed
+//
+// with a policy, every request carries the grants of the client that sent it,
+// and each handle can only be used by the client and through the export that opened it
+//
+// requests also say which user on the client they are made for, whose permissions
+// are checked against the files before the server touches them as its own user
+#[derive(Debug)]
+pub struct GrpcFs {
+    exports: HashMap<String, Export>,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_handle: AtomicU64,
+    // files created for a caller can only be given to them when running as root
+    as_root: bool,
+}
+
+// a directory served under a name, which clients send in the export metadata entry
+#[derive(Debug)]
+pub struct Export {
+    // empty for the default export, served to clients that do not name one
+    name: String,
+    root: PathBuf,
+    read_only: bool,
+    // clients let in and what they may do, everyone and everything without one
+    policy: Option<Arc<Policy>>,
+    // random key of the hash that places directory entries, which keeps names that
+    // collide with another one from being picked on purpose; like file handles, the
+    // offsets handed out do not outlive the server
+    name_key: RandomState,
+}
+
+#[derive(Debug, Clone)]
+struct Handle {
+    file: Arc<fs::File>,
+    // name of the export the file was opened through
+    export: String,
+    // identity of the client that opened the file, none without a policy
+    owner: Option<Arc<str>>,
+    // whether the export and policy let the client change the file when it was opened
+    writable: bool,
+    last_use: Instant,
+}
+
+// how long a handle stays open without being used or renewed, clients renew the handles
+// they hold well within it
+pub const HANDLE_LEASE: Duration = Duration::from_secs(120);
+
+impl GrpcFs {
+    // serves `root` as the default export
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        let export = Export::new("", root, false, None)?;
+        GrpcFs::with_exports(vec![export]).map_err(std::io::Error::other)
+    }
+
+    pub fn with_exports(exports: Vec<Export>) -> Result<Self, String> {
+        let mut by_name = HashMap::new();
+        for export in exports {
+            if by_name.contains_key(&export.name) {
+                return Err(format!("export '{}' is defined twice", export.name));
+            }
+            by_name.insert(export.name.clone(), export);
+        }
+
+        Ok(GrpcFs {
+            exports: by_name,
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            as_root: unsafe { libc::geteuid() } == 0,
+        })
+    }
+
+    // the export a request is for, and the grants of the client that sent it
+    // when the export has a policy
+    fn export<T>(&self, request: &Request<T>) -> Result<(&Export, Option<Grants>), Status> {
+        let name = match request.metadata().get(EXPORT_METADATA_KEY) {
+            Some(name) => name
+                .to_str()
+                .map_err(|_| Status::invalid_argument("invalid export metadata"))?,
+            None => "",
+        };
+        let Some(export) = self.exports.get(name) else {
+            let msg = match name {
+                "" => String::from("no default export, an export must be named"),
+                _ => format!("no export named '{}'", name),
+            };
+            debug!("{}", msg);
+            return Err(errno_status(tonic::Code::NotFound, msg, libc::ENOENT));
+        };
+        let grants = match &export.policy {
+            Some(policy) => Some(policy.authenticate(request)?),
+            None => None,
+        };
+        Ok((export, grants))
+    }
+
+    fn insert_handle(
+        &self,
+        export: &Export,
+        file: fs::File,
+        grants: &Option<Grants>,
+        writable: bool,
+    ) -> u64 {
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let handle = Handle {
+            file: Arc::new(file),
+            export: export.name.clone(),
+            owner: grants.as_ref().map(|grants| grants.identity.clone()),
+            writable,
+            last_use: Instant::now(),
+        };
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    // handles of other clients and other exports look the same as unknown ones,
+    // using a handle renews its lease
+    fn get_handle(
+        &self,
+        export: &Export,
+        fh: u64,
+        grants: &Option<Grants>,
+    ) -> Result<Handle, Status> {
+        let owner = grants.as_ref().map(|grants| grants.identity.clone());
+        match self.handles.lock().unwrap().get_mut(&fh) {
+            Some(handle) if handle.export == export.name && handle.owner == owner => {
+                handle.last_use = Instant::now();
+                Ok(handle.clone())
+            }
+            _ => {
+                debug!("unknown file handle {}", fh);
+                Err(errno_status(
+                    tonic::Code::NotFound,
+                    "bad file handle",
+                    libc::EBADF,
+                ))
+            }
+        }
+    }
+
+    // closes the handles whose lease ran out by `now`, returns how many
+    pub fn expire_handles(&self, now: Instant) -> usize {
+        let mut handles = self.handles.lock().unwrap();
+        let before = handles.len();
+        handles.retain(|fh, handle| {
+            let expired = now.saturating_duration_since(handle.last_use) > HANDLE_LEASE;
+            if expired {
+                debug!("file handle {} expired", fh);
+            }
+            !expired
+        });
+        before - handles.len()
+    }
+
+    // new files belong to the caller, in the group of a set-group-id directory
+    fn chown_created(&self, caller: &Option<Caller>, path: &Path) {
+        let (Some(caller), true) = (caller, self.as_root) else {
+            return;
+        };
+        let gid = match path.parent().map(fs::metadata) {
+            Some(Ok(parent)) if parent.mode() & libc::S_ISGID != 0 => parent.gid(),
+            _ => caller.gid,
+        };
+        if let Err(e) = std::os::unix::fs::lchown(path, Some(caller.uid), Some(gid)) {
+            warn!("failed to chown {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Export {
+    pub fn new(
+        name: impl Into<String>,
+        root: impl AsRef<Path>,
+        read_only: bool,
+        policy: Option<Arc<Policy>>,
+    ) -> std::io::Result<Self> {
+        let name = name.into();
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(std::io::Error::other(format!(
+                "export root {} is not a directory",
+                root.display()
+            )));
+        }
+        match &*name {
+            "" => info!("exporting {}", root.display()),
+            _ => info!("exporting {} as '{}'", root.display(), name),
+        }
+
+        Ok(Export {
+            name,
+            root,
+            read_only,
+            policy,
+            name_key: RandomState::new(),
+        })
+    }
+
+    // where a name is placed among the directory entries, see `entries_after`
+    fn name_hash(&self, name: &OsStr) -> u64 {
+        self.name_key.hash_one(name) >> (2 + RUN_BITS)
+    }
+
+    // translates a client-supplied path into a path under the export root
+    fn resolve(&self, path: &str) -> Result<PathBuf, Status> {
+        let resolved = self.join_root(path)?;
+        self.confine(resolved)
+    }
+
+    // same as `resolve`, but a symlink at the last component is not followed,
+    // for operations on the directory entry itself (unlink, rename, ...)
+    fn resolve_entry(&self, path: &str) -> Result<PathBuf, Status> {
+        let resolved = self.join_root(path)?;
+        match resolved.parent() {
+            Some(parent) if resolved != self.root => {
+                self.confine(parent.to_path_buf())?;
+            }
+            _ => {}
+        }
+        Ok(resolved)
+    }
+
+    // checks the caller may access `path` where it ends up once symlinks are followed
+    fn authorize(
+        &self,
+        grants: &Option<Grants>,
+        path: &Path,
+        access: Access,
+    ) -> Result<(), Status> {
+        self.check_writable(access)?;
+        match grants {
+            Some(grants) => {
+                let real_path = fs::canonicalize(path).unwrap_or_else(|_| self.real_entry(path));
+                self.check(grants, &real_path, access)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // same as `authorize`, but for the directory entry rather than what a symlink at it points to
+    fn authorize_entry(
+        &self,
+        grants: &Option<Grants>,
+        path: &Path,
+        access: Access,
+    ) -> Result<(), Status> {
+        self.check_writable(access)?;
+        match grants {
+            Some(grants) => self.check(grants, &self.real_entry(path), access),
+            None => Ok(()),
+        }
+    }
+
+    // creating, removing and renaming entries changes the directory holding them
+    fn authorize_parent(&self, grants: &Option<Grants>, path: &Path) -> Result<(), Status> {
+        match path.parent() {
+            Some(parent) if path != self.root => self.authorize(grants, parent, Access::Write),
+            _ => self.authorize(grants, &self.root, Access::Write),
+        }
+    }
+
+    fn check_writable(&self, access: Access) -> Result<(), Status> {
+        if access == Access::Write && self.read_only {
+            return Err(errno_status(
+                tonic::Code::PermissionDenied,
+                "read-only export",
+                libc::EROFS,
+            ));
+        }
+        Ok(())
+    }
+
+    fn real_entry(&self, path: &Path) -> PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if path != self.root => fs::canonicalize(parent)
+                .map(|parent| parent.join(name))
+                .unwrap_or_else(|_| path.to_path_buf()),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn check(&self, grants: &Grants, real_path: &Path, access: Access) -> Result<(), Status> {
+        match real_path.strip_prefix(&self.root) {
+            Ok(relative) => grants.check(relative, access),
+            Err(_) => Err(errno_status(
+                tonic::Code::PermissionDenied,
+                "permission denied",
+                libc::EACCES,
+            )),
+        }
+    }
+
+    // every directory on the way to `path` must be searchable by the caller
+    fn check_search(&self, caller: &Option<Caller>, path: &Path) -> Result<(), Status> {
+        let Some(caller) = caller else {
+            return Ok(());
+        };
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root) {
+                break;
+            }
+            match fs::metadata(dir) {
+                Ok(metadata) if !caller.permits(&metadata, MAY_EXEC) => {
+                    return Err(permission_denied(caller, dir, libc::EACCES));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // missing files are let through, the operation itself fails on them
+    fn check_access(&self, caller: &Option<Caller>, path: &Path, mask: u32) -> Result<(), Status> {
+        self.check_search(caller, path)?;
+        match (caller, fs::symlink_metadata(path)) {
+            (Some(caller), Ok(metadata)) if !caller.permits(&metadata, mask) => {
+                Err(permission_denied(caller, path, libc::EACCES))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // adding and removing entries needs write and search permission on their directory,
+    // and entries of sticky directories can only be removed by their owners
+    fn check_parent(
+        &self,
+        caller: &Option<Caller>,
+        path: &Path,
+        removing: bool,
+    ) -> Result<(), Status> {
+        let parent = match path.parent() {
+            Some(parent) if path != self.root => parent,
+            _ => &self.root,
+        };
+        self.check_access(caller, parent, MAY_WRITE | MAY_EXEC)?;
+
+        let (Some(caller), true) = (caller, removing) else {
+            return Ok(());
+        };
+        let sticky = fs::metadata(parent)
+            .is_ok_and(|metadata| metadata.mode() & libc::S_ISVTX != 0 && !caller.owns(&metadata));
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if sticky && !caller.owns(&metadata) => {
+                Err(permission_denied(caller, path, libc::EPERM))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // only owners may change modes and times, and only root may give files away
+    fn check_set_attr(
+        &self,
+        caller: &Option<Caller>,
+        target: Target,
+        request: &mut SetAttrRequest,
+    ) -> Result<(), Status> {
+        let Some(caller) = caller else {
+            return Ok(());
+        };
+        // the file of a handle was reached, and opened for writing if need be, by open
+        let (path, metadata) = match target {
+            Target::File(file) => (Path::new(&request.path), file.metadata()),
+            Target::Path(path) => {
+                if request.size.is_some() {
+                    self.check_access(&Some(caller.clone()), path, MAY_WRITE)?;
+                } else {
+                    self.check_search(&Some(caller.clone()), path)?;
+                }
+                (path, fs::symlink_metadata(path))
+            }
+        };
+        let Ok(metadata) = metadata else {
+            return Ok(());
+        };
+
+        let changes_owner = request.uid.is_some_and(|uid| uid != metadata.uid());
+        let changes_group = request.gid.is_some_and(|gid| {
+            gid != metadata.gid() && !(caller.owns(&metadata) && caller.in_group(gid))
+        });
+        let sets_times = request.atime.is_some() || request.mtime.is_some();
+        if (request.mode.is_some() && !caller.owns(&metadata))
+            || ((changes_owner || changes_group) && !caller.is_root())
+            || (sets_times && !caller.owns(&metadata) && !caller.permits(&metadata, MAY_WRITE))
+        {
+            return Err(permission_denied(caller, path, libc::EPERM));
+        }
+
+        // like chmod(2), the set-group-id bit is dropped for groups the caller is not in
+        let group = request.gid.unwrap_or(metadata.gid());
+        if let Some(mode) = &mut request.mode {
+            if !caller.is_root() && !caller.in_group(group) {
+                *mode &= !libc::S_ISGID;
+            }
+        }
+        Ok(())
+    }
+
+    fn join_root(&self, path: &str) -> Result<PathBuf, Status> {
+        let mut resolved = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    if resolved == self.root {
+                        debug!("{} escapes export root", path);
+                        return Err(errno_status(
+                            tonic::Code::PermissionDenied,
+                            "permission denied",
+                            libc::EACCES,
+                        ));
+                    }
+                    resolved.pop();
+                }
+                Component::Prefix(_) => {
+                    return Err(errno_status(
+                        tonic::Code::PermissionDenied,
+                        "permission denied",
+                        libc::EACCES,
+                    ))
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    // rejects paths which leave the export root through symlinks, and paths that cannot
+    // be followed to where they end up
+    fn confine(&self, path: PathBuf) -> Result<PathBuf, Status> {
+        let unresolved = |e: &std::io::Error| {
+            let msg = format!("failed to resolve {}: {}", path.display(), e);
+            debug!("{}", msg);
+            io_status(msg, e)
+        };
+        let real_path = match fs::canonicalize(&path) {
+            Ok(real_path) => real_path,
+            // a path that does not exist (yet) is where its parent directory is, and a
+            // dangling symlink where it points, which must be found in turn
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let entry = match (path.parent(), fs::read_link(&path)) {
+                    (Some(parent), Ok(target)) => parent.join(target),
+                    _ => path.clone(),
+                };
+                let (Some(parent), Some(name)) = (entry.parent(), entry.file_name()) else {
+                    return Err(unresolved(&e));
+                };
+                fs::canonicalize(parent)
+                    .map(|parent| parent.join(name))
+                    .map_err(|e| unresolved(&e))?
+            }
+            Err(e) => return Err(unresolved(&e)),
+        };
+
+        if !real_path.starts_with(&self.root) {
+            debug!(
+                "{} points outside of export root: {}",
+                path.display(),
+                real_path.display()
+            );
+            return Err(errno_status(
+                tonic::Code::PermissionDenied,
+                "permission denied",
+                libc::EACCES,
+            ));
+        }
+        Ok(path)
+    }
+}
+
+// the client names the export it uses in this metadata entry
+pub const EXPORT_METADATA_KEY: &str = "export";
+
+// the errno of a failed operation is sent in this metadata entry,
+// so the client can hand it to the kernel as is
+pub const ERRNO_METADATA_KEY: &str = "errno";
+
+pub(crate) fn errno_status(code: tonic::Code, msg: impl Into<String>, errno: i32) -> Status {
+    let mut status = Status::new(code, msg);
+    status
+        .metadata_mut()
+        .insert(ERRNO_METADATA_KEY, errno.into());
+    status
+}
+
+fn permission_denied(caller: &Caller, path: &Path, errno: i32) -> Status {
+    debug!("uid {} may not access {}", caller.uid, path.display());
+    errno_status(tonic::Code::PermissionDenied, "permission denied", errno)
+}
+
+fn io_status(msg: impl Into<String>, e: &std::io::Error) -> Status {
+    let code = match e.kind() {
+        std::io::ErrorKind::NotFound => tonic::Code::NotFound,
+        std::io::ErrorKind::PermissionDenied => tonic::Code::PermissionDenied,
+        std::io::ErrorKind::AlreadyExists => tonic::Code::AlreadyExists,
+        std::io::ErrorKind::InvalidInput => tonic::Code::InvalidArgument,
+        _ => tonic::Code::Internal,
+    };
+
+    match e.raw_os_error() {
+        Some(errno) => errno_status(code, msg, errno),
+        None => Status::new(code, msg),
+    }
+}
+
+fn file_type(file_type: fs::FileType) -> FileType {
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_fifo() {
+        FileType::Fifo
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else {
+        FileType::Regular
+    }
+}
+
+fn attr_from_metadata(metadata: &fs::Metadata) -> Attr {
+    Attr {
+        inode: metadata.ino(),
+        dev: metadata.dev(),
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        kind: file_type(metadata.file_type()).into(),
+        permission: metadata.permissions().mode(),
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+        atime: Some(rpc_fs::Timestamp {
+            sec: metadata.atime(),
+            nsec: metadata.atime_nsec() as u32,
+        }),
+        mtime: Some(rpc_fs::Timestamp {
+            sec: metadata.mtime(),
+            nsec: metadata.mtime_nsec() as u32,
+        }),
+        ctime: Some(rpc_fs::Timestamp {
+            sec: metadata.ctime(),
+            nsec: metadata.ctime_nsec() as u32,
+        }),
+        // not every filesystem records the birth time
+        crtime: metadata
+            .created()
+            .ok()
+            .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+            .map(|created| rpc_fs::Timestamp {
+                sec: created.as_secs() as i64,
+                nsec: created.subsec_nanos(),
+            }),
+    }
+}
+
+// upper bound of entries sent in one read_dir reply, the client asks again for the rest
+const READ_DIR_BATCH: usize = 1024;
+
+// upper bound of a unary read, larger reads go through read_stream; the reply has to fit
+// in the 4 MiB a tonic client decodes by default, framing included
+const MAX_READ_SIZE: i64 = 2 << 20;
+
+// size of the chunks sent by read_stream
+const READ_STREAM_CHUNK: u64 = 256 << 10;
+
+// the size of a read comes from the client, so it is checked before anything is allocated
+fn check_read_size(fh: u64, size: i64, max: i64) -> Result<(), Status> {
+    if (0..=max).contains(&size) {
+        return Ok(());
+    }
+    let msg = format!("invalid read size {} on handle {}", size, fh);
+    debug!("{}", msg);
+    Err(errno_status(
+        tonic::Code::InvalidArgument,
+        msg,
+        libc::EINVAL,
+    ))
+}
+
+// read_at may return less than asked before the end of the file,
+// so it is called again until the buffer is full or nothing is left
+fn read_full_at(file: &fs::File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read_at(&mut buffer[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+// the offset of a directory entry is made of a hash of its name, so that it stays valid
+// while other entries are created and removed between paginated calls, and of its place
+// among the names that share the hash, in the low RUN_BITS; this leaves room for 0 as
+// the start of the directory and the client's offsets of "." and ".."
+const RUN_BITS: u32 = 10;
+
+// the next batch of entries after `offset`, in offset order, placed by `name_hash` which
+// must fit in 64 - 2 - RUN_BITS bits; only the batch is sorted, and the run of names
+// sharing the hash of the last entry taken is gone through again to find its place
+fn entries_after(
+    path: &Path,
+    offset: i64,
+    name_hash: impl Fn(&OsStr) -> u64,
+) -> std::io::Result<Vec<(u64, fs::DirEntry)>> {
+    let after = offset.max(0) as u64;
+    let from = after.saturating_sub(1) >> RUN_BITS;
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let hash = name_hash(&entry.file_name());
+        if hash >= from {
+            entries.push((hash, entry));
+        }
+    }
+
+    let by_place = |(a_hash, a): &(u64, fs::DirEntry), (b_hash, b): &(u64, fs::DirEntry)| {
+        a_hash
+            .cmp(b_hash)
+            .then_with(|| a.file_name().cmp(&b.file_name()))
+    };
+    if entries.len() > READ_DIR_BATCH {
+        entries.select_nth_unstable_by(READ_DIR_BATCH, by_place);
+        entries.truncate(READ_DIR_BATCH);
+    }
+    entries.sort_unstable_by(by_place);
+
+    let mut placed = Vec::with_capacity(entries.len());
+    let mut run = None;
+    for (hash, entry) in entries {
+        let place = match run {
+            Some((run_hash, place)) if run_hash == hash => place + 1,
+            _ => 0,
+        };
+        run = Some((hash, place));
+        let offset = (hash << RUN_BITS) + place + 1;
+        if offset > after {
+            placed.push((offset, entry));
+        }
+    }
+    Ok(placed)
+}
+
+// builds the options for the open(2) flags sent by the client
+fn open_options(flags: i32) -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => options.write(true),
+        libc::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+
+    // O_NOFOLLOW keeps symlinks from being followed out of the export root,
+    // and O_NONBLOCK keeps a FIFO from blocking the server
+    let passthrough = libc::O_APPEND | libc::O_TRUNC | libc::O_CREAT | libc::O_EXCL;
+    options.custom_flags(flags & passthrough | libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    options
+}
+
+// what a setattr changes, an open file or whatever is at a path, symlinks included
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    File(&'a fs::File),
+    Path(&'a Path),
+}
+
+fn set_attr(target: Target, request: &SetAttrRequest) -> std::io::Result<fs::Metadata> {
+    // truncate first, as chmod may take away the write permission
+    if let Some(size) = request.size {
+        match target {
+            Target::File(file) => file.set_len(size)?,
+            Target::Path(path) => open_options(libc::O_WRONLY).open(path)?.set_len(size)?,
+        }
+    }
+
+    if request.uid.is_some() || request.gid.is_some() {
+        match target {
+            Target::File(file) => std::os::unix::fs::fchown(file, request.uid, request.gid)?,
+            Target::Path(path) => std::os::unix::fs::lchown(path, request.uid, request.gid)?,
+        }
+    }
+
+    if let Some(mode) = request.mode {
+        let permissions = fs::Permissions::from_mode(mode & 0o7777);
+        match target {
+            Target::File(file) => file.set_permissions(permissions)?,
+            Target::Path(path) => {
+                // chmod always follows symlinks, and Linux has no lchmod
+                if fs::symlink_metadata(path)?.file_type().is_symlink() {
+                    return Err(std::io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+                }
+                fs::set_permissions(path, permissions)?;
+            }
+        }
+    }
+
+    if request.atime.is_some() || request.mtime.is_some() {
+        let timespec = |time: &Option<rpc_fs::Timestamp>| match time {
+            Some(time) => libc::timespec {
+                tv_sec: time.sec,
+                tv_nsec: time.nsec.into(),
+            },
+            None => libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+        };
+        let times = [timespec(&request.atime), timespec(&request.mtime)];
+        let result = match target {
+            Target::File(file) => unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) },
+            Target::Path(path) => {
+                let path = CString::new(path.as_os_str().as_bytes())?;
+                let flags = libc::AT_SYMLINK_NOFOLLOW;
+                unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), flags) }
+            }
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    match target {
+        Target::File(file) => file.metadata(),
+        Target::Path(path) => fs::symlink_metadata(path),
+    }
+}
 
 #[tonic::async_trait]
 impl RpcFs for GrpcFs {
@@ -27,36 +781,28 @@ impl RpcFs for GrpcFs {
         request: Request<GetAttrRequest>,
     ) -> Result<Response<GetAttrReply>, Status> {
         debug!("grpc: get_attr");
-        let path = request.into_inner().path;
-        match fs::metadata(&path) {
-            Ok(dentry_metadata) => {
-                let reply = |kind| GetAttrReply {
-                    attributes: Some(Attr {
-                        inode: dentry_metadata.ino(),
-                        size: dentry_metadata.size(),
-                        blocks: dentry_metadata.blocks(),
-                        kind,
-                        permission: dentry_metadata.permissions().mode(),
-                        nlink: dentry_metadata.nlink() as u32,
-                        uid: dentry_metadata.uid(),
-                        gid: dentry_metadata.gid(),
-                        rdev: dentry_metadata.rdev() as u32,
-                        blksize: dentry_metadata.blksize() as u32,
-                    }),
-                };
-
-                return Ok(Response::new(if dentry_metadata.is_dir() {
-                    reply(FileType::Directory.into())
-                } else {
-                    reply(FileType::Regular.into())
-                }));
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let GetAttrRequest { path, fh } = request.into_inner();
+        let metadata = match fh {
+            Some(fh) => self.get_handle(export, fh, &grants)?.file.metadata(),
+            None => {
+                let path = export.resolve_entry(&path)?;
+                export.authorize_entry(&grants, &path, Access::Lookup)?;
+                export.check_search(&caller, &path)?;
+                fs::symlink_metadata(&path)
             }
-            Err(_) => {
-                debug!("failed to get metadata of {}", path);
+        };
+        match metadata {
+            Ok(dentry_metadata) => Ok(Response::new(GetAttrReply {
+                attributes: Some(attr_from_metadata(&dentry_metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to get metadata of {}: {}", path, e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
             }
         }
-
-        Err(Status::new(tonic::Code::NotFound, "not found"))
     }
 
     async fn look_up(
@@ -64,36 +810,21 @@ impl RpcFs for GrpcFs {
         request: Request<LookUpRequest>,
     ) -> Result<Response<LookUpReply>, Status> {
         debug!("grpc: lookup");
-        let path = request.into_inner().path;
-        match fs::metadata(&path) {
-            Ok(dentry_metadata) => {
-                let reply = |kind| LookUpReply {
-                    attributes: Some(Attr {
-                        inode: dentry_metadata.ino(),
-                        size: dentry_metadata.size(),
-                        blocks: dentry_metadata.blocks(),
-                        kind,
-                        permission: dentry_metadata.permissions().mode(),
-                        nlink: dentry_metadata.nlink() as u32,
-                        uid: dentry_metadata.uid(),
-                        gid: dentry_metadata.gid(),
-                        rdev: dentry_metadata.rdev() as u32,
-                        blksize: dentry_metadata.blksize() as u32,
-                    }),
-                };
-
-                return Ok(Response::new(if dentry_metadata.is_dir() {
-                    reply(FileType::Directory.into())
-                } else {
-                    reply(FileType::Regular.into())
-                }));
-            }
-            Err(_) => {
-                debug!("failed to get metadata of {}", path);
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let path = export.resolve_entry(&request.into_inner().path)?;
+        export.authorize_entry(&grants, &path, Access::Lookup)?;
+        export.check_search(&caller, &path)?;
+        match fs::symlink_metadata(&path) {
+            Ok(dentry_metadata) => Ok(Response::new(LookUpReply {
+                attributes: Some(attr_from_metadata(&dentry_metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to get metadata of {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
             }
         }
-
-        Err(Status::new(tonic::Code::NotFound, "not found"))
     }
 
     async fn read_dir(
@@ -101,46 +832,52 @@ impl RpcFs for GrpcFs {
         request: Request<ReadDirRequest>,
     ) -> Result<Response<ReadDirReply>, Status> {
         debug!("grpc: read_dir");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
         let ReadDirRequest { path, offset } = request.into_inner();
 
-        let path = Path::new(&path);
-        if path.is_dir() {
-            let dirs = match fs::read_dir(path) {
-                Ok(dir) => dir,
-                Err(_) => {
-                    let msg = format!("failed to read directory {}", path.display());
-                    debug!("{}", msg);
-                    return Err(Status::new(tonic::Code::Internal, msg));
-                }
-            };
+        let path = export.resolve(&path)?;
+        export.authorize(&grants, &path, Access::Read)?;
+        export.check_access(&caller, &path, MAY_READ)?;
+        let dirs = match entries_after(&path, offset, |name| export.name_hash(name)) {
+            Ok(dir) => dir,
+            Err(e) => {
+                let msg = format!("failed to read directory {}: {}", path.display(), e);
+                debug!("{}", msg);
+                return Err(io_status(msg, &e));
+            }
+        };
+        let dir_dev = fs::metadata(&path)
+            .map(|metadata| metadata.dev())
+            .unwrap_or_default();
 
-            let entries: Vec<DEntry> = dirs
-                .filter_map(|e| e.ok())
-                .skip(offset as usize)
-                .enumerate()
-                .map(|(idx, entry)| {
-                    let kind = if entry.path().is_dir() {
-                        FileType::Directory
-                    } else {
-                        FileType::Regular
-                    };
-
-                    let file_name = (*entry.file_name().to_string_lossy()).to_string();
-                    let inode = entry.ino();
-                    debug!("inode: {}, file_name: {:?}", inode, file_name);
-
-                    rpc_fs::DEntry {
-                        inode,
-                        offset: idx as u64 + 1,
-                        file_name,
-                        kind: kind.into(),
-                    }
-                })
-                .collect();
+        let entries: Vec<DEntry> = dirs
+            .into_iter()
+            .map(|(offset, entry)| {
+                let kind = entry
+                    .file_type()
+                    .map(file_type)
+                    .unwrap_or(FileType::Regular);
 
-            return Ok(Response::new(ReadDirReply { entries }));
-        }
-        Err(Status::new(tonic::Code::NotFound, "not found"))
+                let file_name = (*entry.file_name().to_string_lossy()).to_string();
+                // the entry's own ids, which differ from the directory's at mount points
+                let (dev, inode) = match entry.metadata() {
+                    Ok(metadata) => (metadata.dev(), metadata.ino()),
+                    Err(_) => (dir_dev, entry.ino()),
+                };
+                debug!("inode: {}, file_name: {:?}", inode, file_name);
+
+                rpc_fs::DEntry {
+                    inode,
+                    offset,
+                    file_name,
+                    kind: kind.into(),
+                    dev,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(ReadDirReply { entries }))
     }
 
     async fn read_dir_plus(
@@ -148,90 +885,1313 @@ impl RpcFs for GrpcFs {
         request: Request<ReadDirRequest>,
     ) -> Result<Response<ReadDirPlusReply>, Status> {
         debug!("grpc: read_dir_plus");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
         let ReadDirRequest { path, offset } = request.into_inner();
 
-        let path = Path::new(&path);
-        if path.is_dir() {
-            let dirs = match fs::read_dir(path) {
-                Ok(dir) => dir,
-                Err(_) => {
-                    let msg = format!("failed to read directory {}", path.display());
-                    debug!("{}", msg);
-                    return Err(Status::new(tonic::Code::Internal, msg));
-                }
-            };
+        let path = export.resolve(&path)?;
+        export.authorize(&grants, &path, Access::Read)?;
+        export.check_access(&caller, &path, MAY_READ | MAY_EXEC)?;
+        let dirs = match entries_after(&path, offset, |name| export.name_hash(name)) {
+            Ok(dir) => dir,
+            Err(e) => {
+                let msg = format!("failed to read directory {}: {}", path.display(), e);
+                debug!("{}", msg);
+                return Err(io_status(msg, &e));
+            }
+        };
+        let dir_dev = fs::metadata(&path)
+            .map(|metadata| metadata.dev())
+            .unwrap_or_default();
 
-            let entries: Vec<DEntryPlus> = dirs
-                .filter_map(|e| e.ok())
-                .skip(offset as usize)
-                .enumerate()
-                .map(|(idx, entry)| {
-                    let kind = if entry.path().is_dir() {
-                        FileType::Directory
-                    } else {
-                        FileType::Regular
-                    };
-
-                    let file_name = (*entry.file_name().to_string_lossy()).to_string();
-                    let inode = entry.ino();
-                    debug!("inode: {}, file_name: {:?}", inode, file_name);
-
-                    let metadata = fs::metadata(path.join(&file_name));
-                    let attrs = match metadata {
-                        Ok(dentry_metadata) => Some(rpc_fs::Attr {
-                            inode: dentry_metadata.ino(),
-                            size: dentry_metadata.size(),
-                            blocks: dentry_metadata.blocks(),
-                            kind: kind.into(),
-                            permission: dentry_metadata.permissions().mode(),
-                            nlink: dentry_metadata.nlink() as u32,
-                            uid: dentry_metadata.uid(),
-                            gid: dentry_metadata.gid(),
-                            rdev: dentry_metadata.rdev() as u32,
-                            blksize: dentry_metadata.blksize() as u32,
-                        }),
-                        Err(_) => None,
-                    };
-
-                    rpc_fs::DEntryPlus {
-                        inode,
-                        offset: idx as u64 + 1,
-                        name: file_name,
-                        kind: kind.into(),
-                        attr: attrs,
-                    }
-                })
-                .collect();
+        let entries: Vec<DEntryPlus> = dirs
+            .into_iter()
+            .map(|(offset, entry)| {
+                let kind = entry
+                    .file_type()
+                    .map(file_type)
+                    .unwrap_or(FileType::Regular);
 
-            return Ok(Response::new(ReadDirPlusReply { entries }));
-        }
-        Err(Status::new(tonic::Code::NotFound, "not found"))
+                let file_name = (*entry.file_name().to_string_lossy()).to_string();
+                let metadata = entry.metadata();
+                let (dev, inode) = match &metadata {
+                    Ok(metadata) => (metadata.dev(), metadata.ino()),
+                    Err(_) => (dir_dev, entry.ino()),
+                };
+                debug!("inode: {}, file_name: {:?}", inode, file_name);
+
+                rpc_fs::DEntryPlus {
+                    inode,
+                    offset,
+                    name: file_name,
+                    kind: kind.into(),
+                    attr: metadata.ok().as_ref().map(attr_from_metadata),
+                    dev,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(ReadDirPlusReply { entries }))
     }
 
     async fn open(&self, request: Request<OpenRequest>) -> Result<Response<OpenReply>, Status> {
         debug!("grpc: open");
-        let OpenRequest { path, .. } = request.into_inner();
-        let path = Path::new(&path);
-        if path.exists() {
-            return Ok(Response::new(OpenReply { fd: 0 }));
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let OpenRequest { path, flags } = request.into_inner();
+        let path = export.resolve(&path)?;
+        let access = if flags as i32 & (libc::O_ACCMODE | libc::O_TRUNC) == libc::O_RDONLY {
+            Access::Read
+        } else {
+            Access::Write
+        };
+        export.authorize(&grants, &path, access)?;
+        let writable =
+            access == Access::Write || export.authorize(&grants, &path, Access::Write).is_ok();
+        let mask = match flags as i32 & libc::O_ACCMODE {
+            libc::O_WRONLY => MAY_WRITE,
+            libc::O_RDWR => MAY_READ | MAY_WRITE,
+            _ => MAY_READ,
+        };
+        let mask = if flags as i32 & libc::O_TRUNC != 0 {
+            mask | MAY_WRITE
+        } else {
+            mask
+        };
+        export.check_access(&caller, &path, mask)?;
+
+        let opened = open_options(flags as i32)
+            .open(&path)
+            .and_then(|file| Ok((file.metadata()?, file)));
+        match opened {
+            Ok((metadata, file)) => Ok(Response::new(OpenReply {
+                fh: self.insert_handle(export, file, &grants, writable),
+                attributes: Some(attr_from_metadata(&metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to open {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
         }
-        Err(Status::new(tonic::Code::NotFound, "not found"))
     }
 
     async fn read(&self, request: Request<ReadRequest>) -> Result<Response<ReadReply>, Status> {
         debug!("grpc: read");
-        let ReadRequest { path, offset, size } = request.into_inner();
-        let path = Path::new(&path);
-
-        if path.is_file() {
-            if let Ok(file) = fs::File::open(path) {
-                let mut buffer = vec![0; size as usize];
-                if file.read_at(&mut buffer, offset).is_ok() {
-                    return Ok(Response::new(ReadReply { data: buffer }));
+        let (export, grants) = self.export(&request)?;
+        let ReadRequest { fh, offset, size } = request.into_inner();
+        check_read_size(fh, size, MAX_READ_SIZE)?;
+        let file = self.get_handle(export, fh, &grants)?.file;
+
+        let mut buffer = vec![0; size as usize];
+        match read_full_at(&file, &mut buffer, offset) {
+            Ok(read) => {
+                // short at the end of the file
+                buffer.truncate(read);
+                Ok(Response::new(ReadReply { data: buffer }))
+            }
+            Err(e) => {
+                let msg = format!("failed to read from handle {}: {}", fh, e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    type ReadStreamStream = Pin<Box<dyn Stream<Item = Result<ReadReply, Status>> + Send>>;
+
+    async fn read_stream(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> Result<Response<Self::ReadStreamStream>, Status> {
+        debug!("grpc: read_stream");
+        let (export, grants) = self.export(&request)?;
+        let ReadRequest { fh, offset, size } = request.into_inner();
+        check_read_size(fh, size, i64::MAX)?;
+        let file = self.get_handle(export, fh, &grants)?.file;
+
+        // one chunk is read at a time, as the client takes them
+        let chunks = stream::unfold((offset, size as u64), move |(offset, remaining)| {
+            let file = file.clone();
+            async move {
+                if remaining == 0 {
+                    return None;
                 }
+                let mut buffer = vec![0; remaining.min(READ_STREAM_CHUNK) as usize];
+                match read_full_at(&file, &mut buffer, offset) {
+                    Ok(0) => None,
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        let next = (offset + read as u64, remaining - read as u64);
+                        Some((Ok(ReadReply { data: buffer }), next))
+                    }
+                    Err(e) => {
+                        let msg = format!("failed to read from handle {}: {}", fh, e);
+                        debug!("{}", msg);
+                        Some((Err(io_status(msg, &e)), (offset, 0)))
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(chunks)))
+    }
+
+    async fn write(&self, request: Request<WriteRequest>) -> Result<Response<WriteReply>, Status> {
+        debug!("grpc: write");
+        let (export, grants) = self.export(&request)?;
+        let WriteRequest { fh, offset, data } = request.into_inner();
+        let file = self.get_handle(export, fh, &grants)?.file;
+
+        match file.write_all_at(&data, offset) {
+            Ok(_) => Ok(Response::new(WriteReply {
+                written: data.len() as u32,
+            })),
+            Err(e) => {
+                let msg = format!("failed to write to handle {}: {}", fh, e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn release(
+        &self,
+        request: Request<ReleaseRequest>,
+    ) -> Result<Response<ReleaseReply>, Status> {
+        debug!("grpc: release");
+        let (export, grants) = self.export(&request)?;
+        let fh = request.into_inner().fh;
+        self.get_handle(export, fh, &grants)?;
+
+        // the file is closed once in-flight reads and writes drop their references
+        match self.handles.lock().unwrap().remove(&fh) {
+            Some(_) => Ok(Response::new(ReleaseReply {})),
+            None => {
+                debug!("unknown file handle {}", fh);
+                Err(errno_status(
+                    tonic::Code::NotFound,
+                    "bad file handle",
+                    libc::EBADF,
+                ))
+            }
+        }
+    }
+
+    async fn renew_handles(
+        &self,
+        request: Request<RenewHandlesRequest>,
+    ) -> Result<Response<RenewHandlesReply>, Status> {
+        debug!("grpc: renew_handles");
+        let (export, grants) = self.export(&request)?;
+        // handles released or expired meanwhile are skipped, they are gone either way
+        for fh in request.into_inner().fhs {
+            let _ = self.get_handle(export, fh, &grants);
+        }
+        Ok(Response::new(RenewHandlesReply {}))
+    }
+
+    async fn create(
+        &self,
+        request: Request<CreateRequest>,
+    ) -> Result<Response<CreateReply>, Status> {
+        debug!("grpc: create");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let CreateRequest { path, mode, flags } = request.into_inner();
+        let path = export.resolve_entry(&path)?;
+        export.authorize_parent(&grants, &path)?;
+        let writable = export.authorize(&grants, &path, Access::Write).is_ok();
+        // without O_EXCL an existing file is just opened
+        let existed = fs::symlink_metadata(&path).is_ok();
+        if existed && flags as i32 & libc::O_EXCL == 0 {
+            let mask = match flags as i32 & libc::O_ACCMODE {
+                libc::O_WRONLY => MAY_WRITE,
+                libc::O_RDWR => MAY_READ | MAY_WRITE,
+                _ => MAY_READ,
+            };
+            export.check_access(&caller, &path, mask)?;
+        } else {
+            export.check_parent(&caller, &path, false)?;
+        }
+
+        let file = open_options(flags as i32 | libc::O_CREAT)
+            .mode(mode)
+            .open(&path);
+        if !existed && file.is_ok() {
+            self.chown_created(&caller, &path);
+        }
+        match file.and_then(|file| Ok((file.metadata()?, file))) {
+            Ok((metadata, file)) => Ok(Response::new(CreateReply {
+                attributes: Some(attr_from_metadata(&metadata)),
+                fh: self.insert_handle(export, file, &grants, writable),
+            })),
+            Err(e) => {
+                let msg = format!("failed to create {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn mkdir(&self, request: Request<MkdirRequest>) -> Result<Response<MkdirReply>, Status> {
+        debug!("grpc: mkdir");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let MkdirRequest { path, mode } = request.into_inner();
+        let path = export.resolve_entry(&path)?;
+        export.authorize_parent(&grants, &path)?;
+        export.check_parent(&caller, &path, false)?;
+
+        let created = fs::DirBuilder::new().mode(mode).create(&path);
+        if created.is_ok() {
+            self.chown_created(&caller, &path);
+        }
+        match created.and_then(|_| fs::metadata(&path)) {
+            Ok(metadata) => Ok(Response::new(MkdirReply {
+                attributes: Some(attr_from_metadata(&metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to create directory {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn unlink(
+        &self,
+        request: Request<UnlinkRequest>,
+    ) -> Result<Response<UnlinkReply>, Status> {
+        debug!("grpc: unlink");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let path = export.resolve_entry(&request.into_inner().path)?;
+        export.authorize_parent(&grants, &path)?;
+        export.check_parent(&caller, &path, true)?;
+
+        match fs::remove_file(&path) {
+            Ok(_) => Ok(Response::new(UnlinkReply {})),
+            Err(e) => {
+                let msg = format!("failed to unlink {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn rmdir(&self, request: Request<RmdirRequest>) -> Result<Response<RmdirReply>, Status> {
+        debug!("grpc: rmdir");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let path = export.resolve_entry(&request.into_inner().path)?;
+        export.authorize_parent(&grants, &path)?;
+        export.check_parent(&caller, &path, true)?;
+        if path == export.root {
+            return Err(errno_status(
+                tonic::Code::PermissionDenied,
+                "cannot remove export root",
+                libc::EBUSY,
+            ));
+        }
+
+        match fs::remove_dir(&path) {
+            Ok(_) => Ok(Response::new(RmdirReply {})),
+            Err(e) => {
+                let msg = format!("failed to remove directory {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn rename(
+        &self,
+        request: Request<RenameRequest>,
+    ) -> Result<Response<RenameReply>, Status> {
+        debug!("grpc: rename");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let RenameRequest { from, to } = request.into_inner();
+        let from = export.resolve_entry(&from)?;
+        let to = export.resolve_entry(&to)?;
+        export.authorize_parent(&grants, &from)?;
+        export.authorize_parent(&grants, &to)?;
+        export.check_parent(&caller, &from, true)?;
+        export.check_parent(&caller, &to, true)?;
+        if from == export.root || to == export.root {
+            return Err(errno_status(
+                tonic::Code::PermissionDenied,
+                "cannot rename export root",
+                libc::EBUSY,
+            ));
+        }
+
+        match fs::rename(&from, &to) {
+            Ok(_) => Ok(Response::new(RenameReply {})),
+            Err(e) => {
+                let msg = format!(
+                    "failed to rename {} to {}: {}",
+                    from.display(),
+                    to.display(),
+                    e
+                );
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn set_attr(
+        &self,
+        request: Request<SetAttrRequest>,
+    ) -> Result<Response<SetAttrReply>, Status> {
+        debug!("grpc: set_attr");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let mut request = request.into_inner();
+        // an open file is changed through its handle, which works after it was unlinked too
+        let handle = match request.fh {
+            Some(fh) => Some(self.get_handle(export, fh, &grants)?),
+            None => None,
+        };
+        let path;
+        let target = match &handle {
+            Some(handle) if !handle.writable => {
+                let errno = if export.read_only {
+                    libc::EROFS
+                } else {
+                    libc::EACCES
+                };
+                return Err(errno_status(
+                    tonic::Code::PermissionDenied,
+                    "permission denied",
+                    errno,
+                ));
+            }
+            Some(handle) => Target::File(&handle.file),
+            None => {
+                path = export.resolve_entry(&request.path)?;
+                export.authorize(&grants, &path, Access::Write)?;
+                Target::Path(&path)
+            }
+        };
+        export.check_set_attr(&caller, target, &mut request)?;
+
+        match set_attr(target, &request) {
+            Ok(metadata) => Ok(Response::new(SetAttrReply {
+                attributes: Some(attr_from_metadata(&metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to set attributes of {}: {}", request.path, e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn readlink(
+        &self,
+        request: Request<ReadlinkRequest>,
+    ) -> Result<Response<ReadlinkReply>, Status> {
+        debug!("grpc: readlink");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let path = export.resolve_entry(&request.into_inner().path)?;
+        export.authorize_entry(&grants, &path, Access::Read)?;
+        export.check_search(&caller, &path)?;
+
+        match fs::read_link(&path) {
+            Ok(target) => Ok(Response::new(ReadlinkReply {
+                target: target.into_os_string().into_vec(),
+            })),
+            Err(e) => {
+                let msg = format!("failed to read link {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn symlink(
+        &self,
+        request: Request<SymlinkRequest>,
+    ) -> Result<Response<SymlinkReply>, Status> {
+        debug!("grpc: symlink");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let SymlinkRequest { path, target } = request.into_inner();
+        let path = export.resolve_entry(&path)?;
+        export.authorize_parent(&grants, &path)?;
+        export.check_parent(&caller, &path, false)?;
+
+        // the target is stored as-is, symlinks are never followed out of the export root
+        let created = std::os::unix::fs::symlink(OsStr::from_bytes(&target), &path);
+        if created.is_ok() {
+            self.chown_created(&caller, &path);
+        }
+        match created.and_then(|_| fs::symlink_metadata(&path)) {
+            Ok(metadata) => Ok(Response::new(SymlinkReply {
+                attributes: Some(attr_from_metadata(&metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to create symlink {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn mknod(&self, request: Request<MknodRequest>) -> Result<Response<MknodReply>, Status> {
+        debug!("grpc: mknod");
+        let (export, grants) = self.export(&request)?;
+        let caller = Caller::from_metadata(request.metadata())?;
+        let MknodRequest { path, mode, rdev } = request.into_inner();
+        let path = export.resolve_entry(&path)?;
+        export.authorize_parent(&grants, &path)?;
+        export.check_parent(&caller, &path, false)?;
+
+        // device nodes would hand out raw access to the server's devices
+        match mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK => {}
+            _ => {
+                return Err(errno_status(
+                    tonic::Code::PermissionDenied,
+                    "only regular files, FIFOs and sockets can be created",
+                    libc::EPERM,
+                ))
+            }
+        }
+
+        let created = CString::new(path.as_os_str().as_bytes())
+            .map_err(std::io::Error::from)
+            .and_then(|cpath| {
+                if unsafe { libc::mknod(cpath.as_ptr(), mode, rdev.into()) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        if created.is_ok() {
+            self.chown_created(&caller, &path);
+        }
+        match created.and_then(|_| fs::symlink_metadata(&path)) {
+            Ok(metadata) => Ok(Response::new(MknodReply {
+                attributes: Some(attr_from_metadata(&metadata)),
+            })),
+            Err(e) => {
+                let msg = format!("failed to create node {}: {}", path.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
             }
         }
+    }
+
+    // statvfs field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    async fn stat_fs(
+        &self,
+        request: Request<StatFsRequest>,
+    ) -> Result<Response<StatFsReply>, Status> {
+        debug!("grpc: stat_fs");
+        let (export, _) = self.export(&request)?;
+
+        let stat = CString::new(export.root.as_os_str().as_bytes())
+            .map_err(std::io::Error::from)
+            .and_then(|croot| {
+                let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+                if unsafe { libc::statvfs(croot.as_ptr(), &mut stat) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(stat)
+            });
+        match stat {
+            Ok(stat) => Ok(Response::new(StatFsReply {
+                blocks: stat.f_blocks as u64,
+                bfree: stat.f_bfree as u64,
+                bavail: stat.f_bavail as u64,
+                files: stat.f_files as u64,
+                ffree: stat.f_ffree as u64,
+                bsize: stat.f_bsize as u32,
+                namelen: stat.f_namemax as u32,
+                frsize: stat.f_frsize as u32,
+            })),
+            Err(e) => {
+                let msg = format!("failed to stat filesystem {}: {}", export.root.display(), e);
+                debug!("{}", msg);
+                Err(io_status(msg, &e))
+            }
+        }
+    }
+
+    async fn list_exports(
+        &self,
+        request: Request<ListExportsRequest>,
+    ) -> Result<Response<ListExportsReply>, Status> {
+        debug!("grpc: list_exports");
+        let mut exports: Vec<_> = self
+            .exports
+            .values()
+            .filter(|export| match &export.policy {
+                Some(policy) => policy.authenticate(&request).is_ok(),
+                None => true,
+            })
+            .map(|export| ExportInfo {
+                name: export.name.clone(),
+                read_only: export.read_only,
+            })
+            .collect();
+        exports.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(ListExportsReply { exports }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::collections::HashSet;
+
+    const ENTRY_COUNT: usize = 3000;
+
+    fn export_with_files(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        for i in 0..ENTRY_COUNT {
+            fs::File::create(root.join(format!("file-{}", i))).unwrap();
+        }
+        root
+    }
+
+    // the kernel only takes as many entries as fit in its buffer and asks again
+    // from the offset of the last one it took
+    fn take_page<T>(entries: Vec<T>, page: usize) -> Vec<T> {
+        entries.into_iter().take(page).collect()
+    }
+
+    #[tokio::test]
+    async fn read_dir_pages_see_every_entry_once() {
+        let root = export_with_files("read-dir-pages");
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let mut seen = HashSet::new();
+        let mut offset = 0;
+        loop {
+            let request = Request::new(ReadDirRequest {
+                path: String::from("/"),
+                offset,
+            });
+            let entries = grpc_fs
+                .read_dir(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .entries;
+            if entries.is_empty() {
+                break;
+            }
+            for entry in take_page(entries, 97) {
+                assert!(
+                    seen.insert(entry.file_name.clone()),
+                    "{} repeated",
+                    entry.file_name
+                );
+                offset = entry.offset as i64;
+            }
+        }
+
+        assert_eq!(seen.len(), ENTRY_COUNT);
+    }
+
+    #[tokio::test]
+    async fn read_dir_plus_pages_see_every_entry_once() {
+        let root = export_with_files("read-dir-plus-pages");
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let mut seen = HashSet::new();
+        let mut offset = 0;
+        loop {
+            let request = Request::new(ReadDirRequest {
+                path: String::from("/"),
+                offset,
+            });
+            let entries = grpc_fs
+                .read_dir_plus(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .entries;
+            if entries.is_empty() {
+                break;
+            }
+            for entry in take_page(entries, 31) {
+                assert!(entry.attr.is_some());
+                assert!(seen.insert(entry.name.clone()), "{} repeated", entry.name);
+                offset = entry.offset as i64;
+            }
+        }
+
+        assert_eq!(seen.len(), ENTRY_COUNT);
+    }
+
+    #[test]
+    fn names_sharing_a_hash_survive_page_breaks() {
+        let root = TempDir::new("read-dir-collisions");
+        let names = ["a", "b", "c", "d", "e", "f"];
+        for name in names {
+            fs::File::create(root.join(name)).unwrap();
+        }
+        // all names but the first and the last share a hash
+        let name_hash = |name: &OsStr| match name.as_bytes() {
+            b"a" => 1,
+            b"f" => 9,
+            _ => 5,
+        };
+
+        // pages of every size end between names of the run somewhere
+        for page in 1..=names.len() {
+            let mut seen = Vec::new();
+            let mut offset = 0;
+            loop {
+                let entries = entries_after(&root, offset, name_hash).unwrap();
+                if entries.is_empty() {
+                    break;
+                }
+                for (entry_offset, entry) in take_page(entries, page) {
+                    seen.push(entry.file_name().into_string().unwrap());
+                    offset = entry_offset as i64;
+                }
+            }
+            assert_eq!(seen, names, "pages of {}", page);
+        }
+
+        // nobody can tell where a name goes in another export
+        let export = |name| Export::new(name, &root, false, None).unwrap();
+        let name = OsStr::new("a");
+        assert_ne!(export("").name_hash(name), export("other").name_hash(name));
+    }
+
+    #[tokio::test]
+    async fn read_dir_pages_survive_removing_entries() {
+        let root = export_with_files("read-dir-removing");
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        // like rm -r, every entry read is removed before the next page is asked for
+        let mut seen = HashSet::new();
+        let mut offset = 0;
+        loop {
+            let request = Request::new(ReadDirRequest {
+                path: String::from("/"),
+                offset,
+            });
+            let entries = grpc_fs
+                .read_dir(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .entries;
+            if entries.is_empty() {
+                break;
+            }
+            for entry in take_page(entries, 97) {
+                fs::remove_file(root.join(&entry.file_name)).unwrap();
+                assert!(seen.insert(entry.file_name));
+                offset = entry.offset as i64;
+            }
+        }
+
+        let left = fs::read_dir(&root).unwrap().count();
+        assert_eq!(left, 0);
+        assert_eq!(seen.len(), ENTRY_COUNT);
+    }
+
+    #[tokio::test]
+    async fn reads_are_bounded_and_short_at_end_of_file() {
+        let root = TempDir::new("reads-bounded");
+        let contents: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        fs::write(root.join("file"), &contents).unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let request = Request::new(OpenRequest {
+            path: String::from("/file"),
+            flags: libc::O_RDONLY as u32,
+        });
+        let fh = grpc_fs.open(request).await.unwrap().into_inner().fh;
+        let read = |offset, size| ReadRequest { fh, offset, size };
+
+        for size in [-1, MAX_READ_SIZE + 1, i64::MAX] {
+            let status = grpc_fs.read(Request::new(read(0, size))).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+
+        let reply = grpc_fs
+            .read(Request::new(read(999_000, 4096)))
+            .await
+            .unwrap();
+        assert_eq!(reply.into_inner().data, &contents[999_000..]);
+        let reply = grpc_fs
+            .read(Request::new(read(2_000_000, 4096)))
+            .await
+            .unwrap();
+        assert!(reply.into_inner().data.is_empty());
+
+        let mut chunks = grpc_fs
+            .read_stream(Request::new(read(10, i64::MAX)))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut streamed = Vec::new();
+        while let Some(chunk) = futures_util::StreamExt::next(&mut chunks).await {
+            let chunk = chunk.unwrap().data;
+            assert!(chunk.len() as u64 <= READ_STREAM_CHUNK);
+            streamed.extend_from_slice(&chunk);
+        }
+
+        assert_eq!(streamed, &contents[10..]);
+    }
+
+    #[tokio::test]
+    async fn reads_of_the_largest_size_reach_a_default_client() {
+        let root = TempDir::new("reads-largest");
+        let contents: Vec<u8> = (0..MAX_READ_SIZE as u32 + 10).map(|i| i as u8).collect();
+        fs::write(root.join("file"), &contents).unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let server = tonic::transport::Server::builder()
+            .add_service(rpc_fs::rpc_fs_server::RpcFsServer::new(grpc_fs))
+            .serve(address);
+        let server = tokio::spawn(server);
+
+        // a client with tonic's default limits, unlike ours
+        let address = format!("http://{}", address);
+        let mut client = None;
+        for _ in 0..100 {
+            match rpc_fs::rpc_fs_client::RpcFsClient::connect(address.clone()).await {
+                Ok(connected) => {
+                    client = Some(connected);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        let mut client = client.expect("server did not start");
+        let open = OpenRequest {
+            path: String::from("/file"),
+            flags: libc::O_RDONLY as u32,
+        };
+        let fh = client.open(open).await.unwrap().into_inner().fh;
+        let read = ReadRequest {
+            fh,
+            offset: 1,
+            size: MAX_READ_SIZE,
+        };
+        let data = client.read(read).await.unwrap().into_inner().data;
+
+        server.abort();
+        assert_eq!(data, &contents[1..=MAX_READ_SIZE as usize]);
+    }
+
+    #[tokio::test]
+    async fn open_files_are_reached_by_handle_after_unlink() {
+        let root = TempDir::new("open-unlinked");
+        fs::write(root.join("file"), "contents").unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let request = Request::new(OpenRequest {
+            path: String::from("/file"),
+            flags: libc::O_RDWR as u32,
+        });
+        let fh = grpc_fs.open(request).await.unwrap().into_inner().fh;
+        fs::remove_file(root.join("file")).unwrap();
+
+        let get_attr = grpc_fs
+            .get_attr(Request::new(GetAttrRequest {
+                path: String::new(),
+                fh: Some(fh),
+            }))
+            .await;
+        let set_attr = grpc_fs
+            .set_attr(Request::new(SetAttrRequest {
+                size: Some(3),
+                fh: Some(fh),
+                ..SetAttrRequest::default()
+            }))
+            .await;
+
+        assert_eq!(get_attr.unwrap().into_inner().attributes.unwrap().size, 8);
+        assert_eq!(set_attr.unwrap().into_inner().attributes.unwrap().size, 3);
+    }
+
+    #[tokio::test]
+    async fn files_and_directories_are_changed_in_the_export() {
+        let root = TempDir::new("changes");
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let mkdir = MkdirRequest {
+            path: String::from("/dir"),
+            mode: 0o755,
+        };
+        let made = grpc_fs.mkdir(Request::new(mkdir)).await.unwrap();
+        assert_eq!(
+            made.into_inner().attributes.unwrap().kind,
+            FileType::Directory as i32
+        );
+        let create = CreateRequest {
+            path: String::from("/dir/file"),
+            mode: 0o644,
+            flags: (libc::O_RDWR | libc::O_EXCL) as u32,
+        };
+        let fh = grpc_fs
+            .create(Request::new(create))
+            .await
+            .unwrap()
+            .into_inner()
+            .fh;
+        let write = WriteRequest {
+            fh,
+            offset: 2,
+            data: b"ta".to_vec(),
+        };
+        let written = grpc_fs.write(Request::new(write)).await.unwrap();
+        assert_eq!(written.into_inner().written, 2);
+        assert_eq!(fs::read(root.join("dir/file")).unwrap(), b"\0\0ta");
+
+        // a directory moves along with what is in it
+        let rename = RenameRequest {
+            from: String::from("/dir"),
+            to: String::from("/moved"),
+        };
+        grpc_fs.rename(Request::new(rename)).await.unwrap();
+        assert_eq!(fs::read(root.join("moved/file")).unwrap(), b"\0\0ta");
+        assert!(!root.join("dir").exists());
+
+        let unlink = UnlinkRequest {
+            path: String::from("/moved/file"),
+        };
+        grpc_fs.unlink(Request::new(unlink)).await.unwrap();
+        let rmdir = RmdirRequest {
+            path: String::from("/moved"),
+        };
+        grpc_fs.rmdir(Request::new(rmdir)).await.unwrap();
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_changes_carry_their_errno() {
+        let root = TempDir::new("failed-changes");
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), "contents").unwrap();
+        fs::write(root.join("file"), "").unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+        let errno = |status: Status| -> i32 {
+            let errno = status.metadata().get(ERRNO_METADATA_KEY).unwrap();
+            errno.to_str().unwrap().parse().unwrap()
+        };
+
+        let create = CreateRequest {
+            path: String::from("/dir/file"),
+            mode: 0o644,
+            flags: (libc::O_RDWR | libc::O_EXCL) as u32,
+        };
+        let created = grpc_fs.create(Request::new(create)).await;
+        assert_eq!(errno(created.unwrap_err()), libc::EEXIST);
+        let mkdir = MkdirRequest {
+            path: String::from("/dir"),
+            mode: 0o755,
+        };
+        let made = grpc_fs.mkdir(Request::new(mkdir)).await;
+        assert_eq!(errno(made.unwrap_err()), libc::EEXIST);
+
+        let open = OpenRequest {
+            path: String::from("/dir/file"),
+            flags: libc::O_RDONLY as u32,
+        };
+        let fh = grpc_fs
+            .open(Request::new(open))
+            .await
+            .unwrap()
+            .into_inner()
+            .fh;
+        for fh in [fh, 12345] {
+            let write = WriteRequest {
+                fh,
+                offset: 0,
+                data: b"data".to_vec(),
+            };
+            let written = grpc_fs.write(Request::new(write)).await;
+            assert_eq!(errno(written.unwrap_err()), libc::EBADF, "handle {}", fh);
+        }
+
+        let unlink = |path: &str| {
+            grpc_fs.unlink(Request::new(UnlinkRequest {
+                path: String::from(path),
+            }))
+        };
+        assert_eq!(errno(unlink("/missing").await.unwrap_err()), libc::ENOENT);
+        assert_eq!(errno(unlink("/dir").await.unwrap_err()), libc::EISDIR);
+        let rmdir = |path: &str| {
+            grpc_fs.rmdir(Request::new(RmdirRequest {
+                path: String::from(path),
+            }))
+        };
+        assert_eq!(errno(rmdir("/dir").await.unwrap_err()), libc::ENOTEMPTY);
+        assert_eq!(errno(rmdir("/dir/file").await.unwrap_err()), libc::ENOTDIR);
+        assert_eq!(errno(rmdir("/").await.unwrap_err()), libc::EBUSY);
+        let rename = |from: &str, to: &str| {
+            grpc_fs.rename(Request::new(RenameRequest {
+                from: String::from(from),
+                to: String::from(to),
+            }))
+        };
+        let renamed = rename("/missing", "/other").await;
+        assert_eq!(errno(renamed.unwrap_err()), libc::ENOENT);
+        // a directory cannot replace a file, nor be moved into itself
+        let renamed = rename("/dir", "/file").await;
+        assert_eq!(errno(renamed.unwrap_err()), libc::ENOTDIR);
+        let renamed = rename("/dir", "/dir/inside").await;
+        assert_eq!(errno(renamed.unwrap_err()), libc::EINVAL);
+
+        assert_eq!(
+            fs::read_to_string(root.join("dir/file")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[tokio::test]
+    async fn handles_left_behind_by_a_client_expire() {
+        let root = TempDir::new("handles-expire");
+        fs::write(root.join("file"), "contents").unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let open = || {
+            grpc_fs.open(Request::new(OpenRequest {
+                path: String::from("/file"),
+                flags: libc::O_RDONLY as u32,
+            }))
+        };
+        let kept = open().await.unwrap().into_inner().fh;
+        let abandoned = open().await.unwrap().into_inner().fh;
+        let read = |fh| {
+            grpc_fs.read(Request::new(ReadRequest {
+                fh,
+                offset: 0,
+                size: 4,
+            }))
+        };
+
+        let opened = Instant::now();
+        assert_eq!(grpc_fs.expire_handles(opened + HANDLE_LEASE / 2), 0);
+
+        // the client that opened `abandoned` went away without releasing it,
+        // while the other one keeps renewing `kept`
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let renew = RenewHandlesRequest {
+            fhs: vec![kept, 12345],
+        };
+        grpc_fs.renew_handles(Request::new(renew)).await.unwrap();
+        let expiry = opened + HANDLE_LEASE + Duration::from_millis(25);
+        assert_eq!(grpc_fs.expire_handles(expiry), 1);
+
+        assert!(read(kept).await.is_ok());
+        let expired = read(abandoned).await.unwrap_err();
+        let errno = expired.metadata().get(ERRNO_METADATA_KEY).unwrap();
+        assert_eq!(errno.to_str().unwrap(), libc::EBADF.to_string());
+        let released = grpc_fs
+            .release(Request::new(ReleaseRequest { fh: abandoned }))
+            .await;
+        assert!(released.is_err());
+    }
+
+    // an export root next to a directory it must not reach, with symlinks into it
+    fn export_beside_outside(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        fs::create_dir(dir.join("root")).unwrap();
+        fs::create_dir(dir.join("outside")).unwrap();
+        fs::write(dir.join("outside/file"), "outside").unwrap();
+        let root = dir.join("root");
+        std::os::unix::fs::symlink("../outside", root.join("dir-link")).unwrap();
+        std::os::unix::fs::symlink("../outside/file", root.join("file-link")).unwrap();
+        std::os::unix::fs::symlink("../outside/new", root.join("dangling-link")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn paths_are_confined_to_the_export_root() {
+        let dir = export_beside_outside("confined-paths");
+        let export = Export::new("", dir.join("root"), false, None).unwrap();
+
+        for path in ["/..", "/../outside/file", "/dir-link/../../outside/file"] {
+            assert!(export.join_root(path).is_err(), "{} escaped", path);
+            assert!(export.resolve(path).is_err(), "{} escaped", path);
+        }
+        // a symlink in the middle of a path is always followed
+        assert!(export.resolve("/dir-link/file").is_err());
+        assert!(export.resolve_entry("/dir-link/file").is_err());
+        assert!(export.resolve_entry("/dir-link/new").is_err());
+        // one at the end is followed unless the entry itself is meant
+        assert!(export.resolve("/file-link").is_err());
+        assert!(export.resolve("/dangling-link").is_err());
+        assert_eq!(
+            export.resolve_entry("/file-link").unwrap(),
+            export.root.join("file-link")
+        );
+        assert_eq!(
+            export.confine(export.root.join("new")).unwrap(),
+            export.root.join("new")
+        );
+    }
+
+    #[test]
+    fn paths_that_cannot_be_followed_are_refused() {
+        let dir = export_beside_outside("unfollowable-paths");
+        let root = dir.join("root");
+        std::os::unix::fs::symlink("loop-b", root.join("loop-a")).unwrap();
+        std::os::unix::fs::symlink("loop-a", root.join("loop-b")).unwrap();
+        std::os::unix::fs::symlink("../outside/missing/new", root.join("deep-link")).unwrap();
+        fs::create_dir(root.join("locked")).unwrap();
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        let export = Export::new("", &root, false, None).unwrap();
+
+        let errno = |status: Status| -> i32 {
+            let errno = status.metadata().get(ERRNO_METADATA_KEY).unwrap();
+            errno.to_str().unwrap().parse().unwrap()
+        };
+        assert_eq!(errno(export.resolve("/loop-a").unwrap_err()), libc::ELOOP);
+        assert_eq!(errno(export.resolve("/loop-a/x").unwrap_err()), libc::ELOOP);
+        assert_eq!(
+            errno(export.resolve_entry("/loop-a/x").unwrap_err()),
+            libc::ELOOP
+        );
+        // the loop itself can still be removed
+        assert!(export.resolve_entry("/loop-a").is_ok());
+        // where a dangling symlink points must be found too
+        assert!(export.resolve("/deep-link").is_err());
+        assert_eq!(
+            errno(export.resolve("/missing/x").unwrap_err()),
+            libc::ENOENT
+        );
+        // root reads through any permissions
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(
+                errno(export.resolve("/locked/x").unwrap_err()),
+                libc::EACCES
+            );
+        }
+
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn symlinks_do_not_lead_out_of_the_export_root() {
+        let dir = export_beside_outside("confined-symlinks");
+        let grpc_fs = GrpcFs::new(dir.join("root")).unwrap();
+
+        for path in ["/dir-link/new", "/file-link", "/dangling-link"] {
+            let create = CreateRequest {
+                path: String::from(path),
+                mode: 0o644,
+                flags: (libc::O_RDWR | libc::O_TRUNC) as u32,
+            };
+            assert!(
+                grpc_fs.create(Request::new(create)).await.is_err(),
+                "create {}",
+                path
+            );
+            let mkdir = MkdirRequest {
+                path: String::from(path),
+                mode: 0o755,
+            };
+            assert!(
+                grpc_fs.mkdir(Request::new(mkdir)).await.is_err(),
+                "mkdir {}",
+                path
+            );
+        }
+        for path in ["/dir-link/file", "/file-link"] {
+            let open = OpenRequest {
+                path: String::from(path),
+                flags: (libc::O_RDWR | libc::O_TRUNC) as u32,
+            };
+            assert!(
+                grpc_fs.open(Request::new(open)).await.is_err(),
+                "open {}",
+                path
+            );
+            let truncate = SetAttrRequest {
+                path: String::from(path),
+                size: Some(0),
+                ..SetAttrRequest::default()
+            };
+            let truncated = grpc_fs.set_attr(Request::new(truncate)).await;
+            assert!(truncated.is_err(), "truncate {}", path);
+            let chmod = SetAttrRequest {
+                path: String::from(path),
+                mode: Some(0o777),
+                ..SetAttrRequest::default()
+            };
+            let changed = grpc_fs.set_attr(Request::new(chmod)).await;
+            assert!(changed.is_err(), "chmod {}", path);
+        }
+
+        let outside = dir.join("outside");
+        assert_eq!(fs::read_to_string(outside.join("file")).unwrap(), "outside");
+        let mode = fs::metadata(outside.join("file"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_ne!(mode & 0o777, 0o777);
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn callers_are_checked_against_file_modes() {
+        let root = TempDir::new("caller-modes");
+        fs::create_dir(root.join("private")).unwrap();
+        fs::write(root.join("private/file"), "").unwrap();
+        fs::set_permissions(root.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+        let grpc_fs = GrpcFs::new(&root).unwrap();
+
+        let get_attr = |caller: Option<Caller>| {
+            let mut request = Request::new(GetAttrRequest {
+                path: String::from("/private/file"),
+                fh: None,
+            });
+            if let Some(caller) = caller {
+                caller.insert_into(request.metadata_mut());
+            }
+            grpc_fs.get_attr(request)
+        };
+        let stranger = Caller {
+            uid: 4_242_424,
+            gid: 4_242_424,
+            groups: vec![],
+        };
+        let owner = Caller {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            groups: vec![],
+        };
+        let denied = get_attr(Some(stranger)).await.unwrap_err();
+        let allowed = get_attr(Some(owner)).await;
+        let anonymous = get_attr(None).await;
+
+        assert_eq!(denied.code(), tonic::Code::PermissionDenied);
+        assert!(allowed.is_ok());
+        assert!(anonymous.is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_by_the_export_they_name() {
+        let dir = TempDir::new("exports");
+        for name in ["default", "archive", "private"] {
+            fs::create_dir(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join(name), "").unwrap();
+        }
+        fs::write(dir.join("policy"), "token:secret / rw").unwrap();
+        let policy = Arc::new(Policy::load(&dir.join("policy")).unwrap());
+        let grpc_fs = GrpcFs::with_exports(vec![
+            Export::new("", dir.join("default"), false, None).unwrap(),
+            Export::new("archive", dir.join("archive"), true, None).unwrap(),
+            Export::new("private", dir.join("private"), false, Some(policy)).unwrap(),
+        ])
+        .unwrap();
+
+        fn request<T>(export: &str, message: T) -> Request<T> {
+            let mut request = Request::new(message);
+            if !export.is_empty() {
+                request
+                    .metadata_mut()
+                    .insert(EXPORT_METADATA_KEY, export.parse().unwrap());
+            }
+            request
+        }
+        let errno = |status: Status| -> i32 {
+            status
+                .metadata()
+                .get(ERRNO_METADATA_KEY)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let get_attr = |export, path: &str| {
+            grpc_fs.get_attr(request(
+                export,
+                GetAttrRequest {
+                    path: String::from(path),
+                    fh: None,
+                },
+            ))
+        };
+
+        let default = get_attr("", "/default").await;
+        let archive = get_attr("archive", "/archive").await;
+        let crossed = get_attr("archive", "/default").await;
+        let unknown = get_attr("missing", "/").await;
+        let created = grpc_fs
+            .create(request(
+                "archive",
+                CreateRequest {
+                    path: String::from("/new"),
+                    mode: 0o644,
+                    flags: libc::O_RDWR as u32,
+                },
+            ))
+            .await;
+
+        // handles only work through the export that opened them
+        let open = OpenRequest {
+            path: String::from("/default"),
+            flags: libc::O_RDONLY as u32,
+        };
+        let fh = grpc_fs
+            .open(request("", open))
+            .await
+            .unwrap()
+            .into_inner()
+            .fh;
+        let read = |export| {
+            grpc_fs.read(request(
+                export,
+                ReadRequest {
+                    fh,
+                    offset: 0,
+                    size: 1,
+                },
+            ))
+        };
+        let same_export = read("").await;
+        let other_export = read("archive").await;
+
+        // exports with a policy are hidden from clients it does not let in
+        let names = |reply: Response<ListExportsReply>| -> Vec<(String, bool)> {
+            let exports = reply.into_inner().exports;
+            exports.into_iter().map(|e| (e.name, e.read_only)).collect()
+        };
+        let anonymous = grpc_fs
+            .list_exports(request("", ListExportsRequest {}))
+            .await
+            .unwrap();
+        let mut authorized = request("", ListExportsRequest {});
+        authorized
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let authorized = grpc_fs.list_exports(authorized).await.unwrap();
+        let private = get_attr("private", "/private").await;
 
-        Err(Status::new(tonic::Code::NotFound, "not found"))
+        assert!(default.is_ok());
+        assert!(archive.is_ok());
+        assert_eq!(errno(crossed.unwrap_err()), libc::ENOENT);
+        assert_eq!(errno(unknown.unwrap_err()), libc::ENOENT);
+        assert_eq!(errno(created.unwrap_err()), libc::EROFS);
+        assert!(same_export.is_ok());
+        assert_eq!(errno(other_export.unwrap_err()), libc::EBADF);
+        assert_eq!(
+            names(anonymous),
+            [(String::new(), false), (String::from("archive"), true)]
+        );
+        assert_eq!(names(authorized).len(), 3);
+        assert_eq!(private.unwrap_err().code(), tonic::Code::Unauthenticated);
     }
 }
@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+// pem files of the server, read when it starts
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // only clients with a certificate signed by this ca are let in
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTls {
+    pub fn config(&self) -> Result<ServerTlsConfig, Box<dyn Error>> {
+        let identity = Identity::from_pem(read_pem(&self.cert)?, read_pem(&self.key)?);
+        let mut config = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(read_pem(client_ca)?));
+        }
+        Ok(config)
+    }
+}
+
+// pem files of the client, read when it connects
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    // the server certificate must be signed by this ca
+    pub ca: PathBuf,
+    // certificate and key shown to servers that require one
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // name expected in the server certificate instead of the host in the url
+    pub domain: Option<String>,
+}
+
+impl ClientTls {
+    pub fn config(&self) -> Result<ClientTlsConfig, Box<dyn Error>> {
+        let mut config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read_pem(&self.ca)?));
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            config = config.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        }
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain);
+        }
+        Ok(config)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+}